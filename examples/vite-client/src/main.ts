@@ -82,7 +82,8 @@ async function login(
     password,
   });
 
-  if (!loginResult) {
+  if (!loginResult.ok) {
+    console.log(`Login failed: ${loginResult.reason}`);
     return null;
   }
   const { sessionKey, finishLoginRequest } = loginResult;
@@ -239,8 +240,8 @@ function runLocalClientServerDemo(
     password,
   });
 
-  if (!loginResult) {
-    console.log("Login failed: `loginResult` is missing");
+  if (!loginResult.ok) {
+    console.log(`Login failed: ${loginResult.reason}`);
     return;
   }
 
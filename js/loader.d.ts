@@ -0,0 +1,10 @@
+export type Opaque = typeof import("./esm/opaque_wasm.js");
+
+export interface LoadOpaqueOptions {
+  /** Number of worker threads, defaults to `navigator.hardwareConcurrency` */
+  threads?: number;
+}
+
+export function keyStretchingThreadsSupported(): boolean;
+
+export function loadOpaque(options?: LoadOpaqueOptions): Promise<Opaque>;
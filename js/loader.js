@@ -0,0 +1,31 @@
+/**
+ * Whether the build computing the Argon2 lanes on worker threads can run:
+ * the workers share the memory through a `SharedArrayBuffer`, which requires
+ * a cross-origin isolated page, and the main browser thread cannot block
+ * while they compute.
+ */
+export function keyStretchingThreadsSupported() {
+  return (
+    typeof SharedArrayBuffer === "function" &&
+    globalThis.crossOriginIsolated === true &&
+    typeof document === "undefined"
+  );
+}
+
+/**
+ * Loads and initializes the threads build with its worker threads started
+ * where it can run, and the single-threaded build everywhere else.
+ */
+export async function loadOpaque(options = {}) {
+  if (keyStretchingThreadsSupported()) {
+    const opaque = await import("./esm-threads/opaque_wasm.js");
+    await opaque.default();
+    await opaque.initKeyStretchingThreads(
+      options.threads ?? navigator.hardwareConcurrency,
+    );
+    return opaque;
+  }
+  const opaque = await import("./esm/opaque_wasm.js");
+  await opaque.default();
+  return opaque;
+}
@@ -7,6 +7,8 @@ async function fixupPkg() {
   const pkgDir = path.join(process.cwd(), "pkg");
   const esmDir = path.join(pkgDir, "esm");
   const cjsDir = path.join(pkgDir, "cjs");
+  const esmThreadsDir = path.join(pkgDir, "esm-threads");
+  const jsDir = path.join(process.cwd(), "js");
 
   try {
     // Move LICENSE and README.md from `pkg/esm` to `pkg` (overwrite existing)
@@ -37,6 +39,21 @@ async function fixupPkg() {
       } catch (error) {
         console.warn(`❌ Failed to delete pkg/cjs/${file}: ${error}`);
       }
+
+      try {
+        await fs.unlink(path.join(esmThreadsDir, file));
+      } catch (error) {
+        console.warn(`❌ Failed to delete pkg/esm-threads/${file}: ${error}`);
+      }
+    }
+
+    // Copy the loader picking the threads build where it can run
+    for (const file of ["loader.js", "loader.d.ts"]) {
+      try {
+        await fs.copyFile(path.join(jsDir, file), path.join(pkgDir, file));
+      } catch (error) {
+        console.warn(`❌ Failed to copy js/${file}: ${error}`);
+      }
     }
 
     console.log("✅ Package fixup completed successfully!");
@@ -0,0 +1,387 @@
+use std::{array, collections::TryReserveError, convert::TryInto};
+
+use argon2::{Algorithm, Params, Version};
+use blake2::{
+    digest::{
+        block_api::{UpdateCore, VariableOutputCore},
+        block_buffer::LazyBuffer,
+        Digest,
+    },
+    Blake2b512, Blake2bVarCore,
+};
+
+use crate::utils::{now_millis, try_vec};
+
+const SYNC_POINTS: usize = 4;
+const ADDRESSES_IN_BLOCK: usize = 128;
+
+/// 1 KiB Argon2 memory block
+type Block = [u64; 128];
+
+/// Argon2 (RFC 9106) computed a few blocks at a time on memory owned by the
+/// state, with the same output as `argon2::Argon2::hash_password_into`. The
+/// lanes are computed one after the other.
+pub(crate) struct Argon2State {
+    algorithm: Algorithm,
+    version: Version,
+    passes: usize,
+    lanes: usize,
+    lane_len: usize,
+    tag_len: usize,
+    h0: [u8; 64],
+    /// Allocated in the first step
+    memory: Vec<Block>,
+    position: Position,
+    /// Input and output of the address generation of data-independent segments
+    address_input: Block,
+    addresses: Block,
+}
+
+#[derive(Clone, Copy)]
+struct Position {
+    pass: usize,
+    slice: usize,
+    lane: usize,
+    index: usize,
+}
+
+impl Argon2State {
+    /// Number of blocks computed between two checks of the deadline
+    const CHUNK: usize = 256;
+
+    pub(crate) fn new(
+        algorithm: Algorithm,
+        version: Version,
+        params: &Params,
+        password: &[u8],
+        salt: &[u8],
+        secret: Option<&[u8]>,
+        tag_len: usize,
+    ) -> Self {
+        let secret = secret.unwrap_or_default();
+        let mut h0 = Blake2b512::new();
+        for value in [
+            params.p_cost(),
+            tag_len as u32,
+            params.m_cost(),
+            params.t_cost(),
+            version as u32,
+            algorithm as u32,
+        ] {
+            h0.update(value.to_le_bytes());
+        }
+        for input in [password, salt, secret, params.data()] {
+            h0.update((input.len() as u32).to_le_bytes());
+            h0.update(input);
+        }
+
+        let lanes = params.p_cost() as usize;
+        Argon2State {
+            algorithm,
+            version,
+            passes: params.t_cost() as usize,
+            lanes,
+            lane_len: params.block_count() / lanes,
+            tag_len,
+            h0: h0.finalize().into(),
+            memory: Vec::new(),
+            position: Position {
+                pass: 0,
+                slice: 0,
+                lane: 0,
+                index: 2,
+            },
+            address_input: [0; 128],
+            addresses: [0; 128],
+        }
+    }
+
+    fn segment_len(&self) -> usize {
+        self.lane_len / SYNC_POINTS
+    }
+
+    fn total(&self) -> usize {
+        self.passes * self.lanes * self.lane_len
+    }
+
+    fn done(&self) -> usize {
+        if self.memory.is_empty() {
+            return 0;
+        }
+        let Position {
+            pass,
+            slice,
+            lane,
+            index,
+        } = self.position;
+        let segments = (pass * SYNC_POINTS + slice) * self.lanes + lane;
+        segments * self.segment_len() + index
+    }
+
+    pub(crate) fn progress(&self) -> f64 {
+        self.done() as f64 / self.total() as f64
+    }
+
+    /// Returns the tag once all passes are done, fails if the memory cannot
+    /// be allocated.
+    pub(crate) fn run(&mut self, deadline: f64) -> Result<Option<Vec<u8>>, TryReserveError> {
+        if self.memory.is_empty() {
+            self.init_memory()?;
+        }
+        while self.position.pass < self.passes {
+            let end = (self.position.index + Self::CHUNK).min(self.segment_len());
+            while self.position.index < end {
+                self.fill_block();
+                self.position.index += 1;
+            }
+            if self.position.index == self.segment_len() {
+                self.next_segment();
+            }
+            if now_millis() >= deadline {
+                break;
+            }
+        }
+        if self.position.pass < self.passes {
+            return Ok(None);
+        }
+        Ok(Some(self.finalize()))
+    }
+
+    fn init_memory(&mut self) -> Result<(), TryReserveError> {
+        self.memory = try_vec(self.lanes * self.lane_len, [0; 128])?;
+        let mut bytes = [0; 1024];
+        for lane in 0..self.lanes {
+            for column in 0..2 {
+                blake2b_long(
+                    &[
+                        &self.h0,
+                        &(column as u32).to_le_bytes(),
+                        &(lane as u32).to_le_bytes(),
+                    ],
+                    &mut bytes,
+                );
+                let block = &mut self.memory[lane * self.lane_len + column];
+                for (word, chunk) in block.iter_mut().zip(bytes.chunks_exact(8)) {
+                    *word = u64::from_le_bytes(chunk.try_into().unwrap());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_segment(&mut self) {
+        let position = &mut self.position;
+        position.lane += 1;
+        if position.lane == self.lanes {
+            position.lane = 0;
+            position.slice += 1;
+            if position.slice == SYNC_POINTS {
+                position.slice = 0;
+                position.pass += 1;
+            }
+        }
+        // The first two blocks of each lane are computed from H0
+        position.index = if position.pass == 0 && position.slice == 0 {
+            2
+        } else {
+            0
+        };
+    }
+
+    fn data_independent(&self) -> bool {
+        match self.algorithm {
+            Algorithm::Argon2d => false,
+            Algorithm::Argon2i => true,
+            Algorithm::Argon2id => self.position.pass == 0 && self.position.slice < SYNC_POINTS / 2,
+        }
+    }
+
+    fn next_addresses(&mut self) {
+        self.address_input[6] += 1;
+        let zero = [0; 128];
+        self.addresses = compress(&zero, &compress(&zero, &self.address_input));
+    }
+
+    fn fill_block(&mut self) {
+        let Position {
+            pass,
+            slice,
+            lane,
+            index,
+        } = self.position;
+        let segment_len = self.segment_len();
+        let first_segment = pass == 0 && slice == 0;
+
+        let pseudo_rand = if self.data_independent() {
+            let start = if first_segment { 2 } else { 0 };
+            if index == start {
+                self.address_input = [0; 128];
+                let values = [
+                    pass,
+                    lane,
+                    slice,
+                    self.memory.len(),
+                    self.passes,
+                    self.algorithm as usize,
+                ];
+                for (word, value) in self.address_input.iter_mut().zip(values) {
+                    *word = value as u64;
+                }
+            }
+            if index == start || index.is_multiple_of(ADDRESSES_IN_BLOCK) {
+                self.next_addresses();
+            }
+            self.addresses[index % ADDRESSES_IN_BLOCK]
+        } else {
+            let current = lane * self.lane_len + slice * segment_len + index;
+            self.memory[self.previous(current)][0]
+        };
+
+        let ref_lane = if first_segment {
+            lane
+        } else {
+            (pseudo_rand >> 32) as usize % self.lanes
+        };
+        let same_lane = ref_lane == lane;
+        let area = match (pass, same_lane) {
+            (0, _) if slice == 0 => index - 1,
+            (0, true) => slice * segment_len + index - 1,
+            (0, false) => slice * segment_len - usize::from(index == 0),
+            (_, true) => self.lane_len - segment_len + index - 1,
+            (_, false) => self.lane_len - segment_len - usize::from(index == 0),
+        } as u64;
+        let x = ((pseudo_rand & 0xffff_ffff) * (pseudo_rand & 0xffff_ffff)) >> 32;
+        let relative = (area - 1 - ((area * x) >> 32)) as usize;
+        let start = match (pass, slice) {
+            (0, _) | (_, 3) => 0,
+            _ => (slice + 1) * segment_len,
+        };
+        let reference = ref_lane * self.lane_len + (start + relative) % self.lane_len;
+
+        let current = lane * self.lane_len + slice * segment_len + index;
+        let block = compress(
+            &self.memory[self.previous(current)],
+            &self.memory[reference],
+        );
+        let target = &mut self.memory[current];
+        if pass > 0 && self.version == Version::V0x13 {
+            for (word, value) in target.iter_mut().zip(block.iter()) {
+                *word ^= value;
+            }
+        } else {
+            *target = block;
+        }
+    }
+
+    fn previous(&self, current: usize) -> usize {
+        if current.is_multiple_of(self.lane_len) {
+            current + self.lane_len - 1
+        } else {
+            current - 1
+        }
+    }
+
+    fn finalize(&mut self) -> Vec<u8> {
+        let mut last = [0; 128];
+        for lane in 0..self.lanes {
+            let block = &self.memory[lane * self.lane_len + self.lane_len - 1];
+            for (word, value) in last.iter_mut().zip(block.iter()) {
+                *word ^= value;
+            }
+        }
+        let bytes = last
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut tag = vec![0; self.tag_len];
+        blake2b_long(&[&bytes], &mut tag);
+        self.memory = Vec::new();
+        tag
+    }
+}
+
+/// Variable-length hash function H' of RFC 9106
+fn blake2b_long(inputs: &[&[u8]], output: &mut [u8]) {
+    let len = (output.len() as u32).to_le_bytes();
+    if output.len() <= 64 {
+        let mut prefixed = vec![&len[..]];
+        prefixed.extend_from_slice(inputs);
+        blake2b_var(&prefixed, output);
+        return;
+    }
+
+    let mut hasher = Blake2b512::new();
+    hasher.update(len);
+    for input in inputs {
+        hasher.update(input);
+    }
+    let mut v = hasher.finalize();
+    output[..32].copy_from_slice(&v[..32]);
+    let mut position = 32;
+    while output.len() - position > 64 {
+        v = Blake2b512::digest(v);
+        output[position..position + 32].copy_from_slice(&v[..32]);
+        position += 32;
+    }
+    blake2b_var(&[&v], &mut output[position..]);
+}
+
+/// BLAKE2b with the output length of `output`, at most 64 bytes
+fn blake2b_var(inputs: &[&[u8]], output: &mut [u8]) {
+    let mut hasher = Blake2bVarCore::new(output.len()).expect("output length is at most 64");
+    let mut buffer = LazyBuffer::default();
+    for input in inputs {
+        buffer.digest_blocks(input, |blocks| hasher.update_blocks(blocks));
+    }
+    let mut full = Default::default();
+    hasher.finalize_variable_core(&mut buffer, &mut full);
+    output.copy_from_slice(&full[..output.len()]);
+}
+
+/// Compression function G of RFC 9106
+fn compress(x: &Block, y: &Block) -> Block {
+    let mut r = [0; 128];
+    for (word, (x, y)) in r.iter_mut().zip(x.iter().zip(y.iter())) {
+        *word = x ^ y;
+    }
+
+    let mut q = r;
+    for row in 0..8 {
+        permute(&mut q, array::from_fn(|i| row * 16 + i));
+    }
+    for column in 0..8 {
+        permute(&mut q, array::from_fn(|i| column * 2 + i / 2 * 16 + i % 2));
+    }
+
+    for (word, r) in q.iter_mut().zip(r.iter()) {
+        *word ^= r;
+    }
+    q
+}
+
+/// Permutation P of RFC 9106 on the words of `block` at `v`
+fn permute(block: &mut Block, v: [usize; 16]) {
+    for [a, b, c, d] in [
+        [0, 4, 8, 12],
+        [1, 5, 9, 13],
+        [2, 6, 10, 14],
+        [3, 7, 11, 15],
+        [0, 5, 10, 15],
+        [1, 6, 11, 12],
+        [2, 7, 8, 13],
+        [3, 4, 9, 14],
+    ] {
+        let (a, b, c, d) = (v[a], v[b], v[c], v[d]);
+        for (rotation_d, rotation_b) in [(32, 24), (16, 63)] {
+            block[a] = blamka(block[a], block[b]);
+            block[d] = (block[d] ^ block[a]).rotate_right(rotation_d);
+            block[c] = blamka(block[c], block[d]);
+            block[b] = (block[b] ^ block[c]).rotate_right(rotation_b);
+        }
+    }
+}
+
+fn blamka(x: u64, y: u64) -> u64 {
+    let low = (x & 0xffff_ffff) * (y & 0xffff_ffff);
+    x.wrapping_add(y).wrapping_add(low.wrapping_mul(2))
+}
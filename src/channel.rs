@@ -0,0 +1,254 @@
+use std::convert::TryInto;
+
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Nonce,
+};
+use hkdf::Hkdf;
+use opaque_ke::rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    base64::{base64_decode, base64_encode, JsResult},
+    error::Error,
+};
+
+const VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const CHANNEL_ID_LEN: usize = 16;
+const COUNTER_LEN: usize = 8;
+const HEADER_LEN: usize = 1 + CHANNEL_ID_LEN + COUNTER_LEN;
+const STR_CLIENT_TO_SERVER: &[u8] = b"OPAQUE-Secure-Channel-Client-To-Server";
+const STR_SERVER_TO_CLIENT: &[u8] = b"OPAQUE-Secure-Channel-Server-To-Client";
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub enum ChannelRole {
+    #[serde(rename = "client")]
+    Client,
+    #[serde(rename = "server")]
+    Server,
+}
+
+/// One direction of the channel with the counter of the next message.
+struct Direction {
+    channel_id: [u8; CHANNEL_ID_LEN],
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl Direction {
+    /// The key depends on the random id of the sending channel, so channels
+    /// created from the same `sessionKey` never share a key.
+    fn new(hkdf: &Hkdf<Sha512>, label: &[u8], channel_id: [u8; CHANNEL_ID_LEN]) -> Self {
+        let mut key = [0; KEY_LEN];
+        hkdf.expand_multi_info(&[label, &channel_id], &mut key)
+            .expect("key length is a valid HKDF-SHA512 output length");
+        Direction {
+            channel_id,
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            counter: 0,
+        }
+    }
+
+    fn open(
+        &mut self,
+        channel_id: [u8; CHANNEL_ID_LEN],
+        counter: u64,
+        header: &[u8],
+        ciphertext: &[u8],
+        associated_data: &[u8],
+    ) -> JsResult<Vec<u8>> {
+        if channel_id != self.channel_id {
+            return Err(Error::InvalidParameter {
+                context: "Invalid message (other channel)",
+            });
+        }
+        if counter != self.counter {
+            return Err(Error::MessageOutOfOrder {
+                expected: self.counter,
+                received: counter,
+            });
+        }
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: ciphertext,
+                    aad: &[header, associated_data].concat(),
+                },
+            )
+            .map_err(|_| Error::DecryptionFailed {
+                context: "secureChannel open",
+            })?;
+        // The counter only advances for authentic messages
+        self.counter = counter.checked_add(1).ok_or(Error::InvalidState {
+            context: "secureChannel ran out of message counters",
+        })?;
+        Ok(plaintext)
+    }
+}
+
+/// The counter is the nonce, so every key encrypts each counter only once.
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
+
+/// Encrypts messages between the client and the server of a login with keys
+/// derived from the shared `sessionKey`, one for each direction. Messages are
+/// `version || channelId || counter || ciphertext || tag` and have to be opened
+/// in the order they were sealed, so replayed, reordered and dropped messages
+/// are rejected.
+#[wasm_bindgen]
+pub struct SecureChannel {
+    hkdf: Hkdf<Sha512>,
+    receive_label: &'static [u8],
+    send: Direction,
+    /// Derived from the channel id of the first authentic message
+    receive: Option<Direction>,
+}
+
+impl SecureChannel {
+    pub(crate) fn from_session_key(
+        session_key: &[u8],
+        role: ChannelRole,
+        channel_id: [u8; CHANNEL_ID_LEN],
+    ) -> Self {
+        let hkdf = Hkdf::<Sha512>::new(None, session_key);
+        let (send_label, receive_label) = match role {
+            ChannelRole::Client => (STR_CLIENT_TO_SERVER, STR_SERVER_TO_CLIENT),
+            ChannelRole::Server => (STR_SERVER_TO_CLIENT, STR_CLIENT_TO_SERVER),
+        };
+        SecureChannel {
+            send: Direction::new(&hkdf, send_label, channel_id),
+            hkdf,
+            receive_label,
+            receive: None,
+        }
+    }
+
+    pub(crate) fn seal_bytes(
+        &mut self,
+        plaintext: &[u8],
+        associated_data: &[u8],
+    ) -> JsResult<Vec<u8>> {
+        let counter = self.send.counter;
+        let next_counter = counter.checked_add(1).ok_or(Error::InvalidState {
+            context: "secureChannel ran out of message counters",
+        })?;
+        let mut message = vec![VERSION];
+        message.extend_from_slice(&self.send.channel_id);
+        message.extend_from_slice(&counter.to_be_bytes());
+
+        let ciphertext = self
+            .send
+            .cipher
+            .encrypt(
+                &nonce(counter),
+                Payload {
+                    msg: plaintext,
+                    aad: &[&message, associated_data].concat(),
+                },
+            )
+            .map_err(|_| Error::InvalidParameter {
+                context: "Invalid plaintext (too long)",
+            })?;
+        message.extend(ciphertext);
+        self.send.counter = next_counter;
+        Ok(message)
+    }
+
+    pub(crate) fn open_bytes(
+        &mut self,
+        message: &[u8],
+        associated_data: &[u8],
+    ) -> JsResult<Vec<u8>> {
+        if message.len() < HEADER_LEN {
+            return Err(Error::InvalidParameter {
+                context: "Invalid message (too short)",
+            });
+        }
+        let (header, ciphertext) = message.split_at(HEADER_LEN);
+        if header[0] != VERSION {
+            return Err(Error::InvalidParameter {
+                context: "Invalid message (unsupported version)",
+            });
+        }
+        let (channel_id, counter) = header[1..].split_at(CHANNEL_ID_LEN);
+        let channel_id = channel_id.try_into().expect("header has a channel id");
+        let counter = u64::from_be_bytes(counter.try_into().expect("header has a counter"));
+        let mut receive = match self.receive.take() {
+            Some(receive) => receive,
+            None => Direction::new(&self.hkdf, self.receive_label, channel_id),
+        };
+        let plaintext = receive.open(channel_id, counter, header, ciphertext, associated_data);
+        // The channel of the other side is fixed by its first authentic message
+        if receive.counter > 0 {
+            self.receive = Some(receive);
+        }
+        plaintext
+    }
+}
+
+#[wasm_bindgen]
+impl SecureChannel {
+    /// Creates the channel of one side from the base64 encoded `sessionKey`
+    /// returned by `finishClientLogin` or `finishServerLogin`.
+    ///
+    /// Create a single channel per side and login: each channel sends under a
+    /// random channel id, and the other side only opens the messages of the
+    /// first channel it received an authentic message from.
+    #[wasm_bindgen(constructor)]
+    pub fn new(session_key: String, role: ChannelRole) -> Result<SecureChannel, JsError> {
+        let session_key = base64_decode("sessionKey", session_key)?;
+        let mut channel_id = [0; CHANNEL_ID_LEN];
+        OsRng.fill_bytes(&mut channel_id);
+        Ok(SecureChannel::from_session_key(
+            &session_key,
+            role,
+            channel_id,
+        ))
+    }
+
+    /// Encrypts the base64 encoded `plaintext` and returns the base64 encoded
+    /// message. The optional base64 encoded `associatedData` is authenticated
+    /// but not sent.
+    pub fn seal(
+        &mut self,
+        plaintext: String,
+        associated_data: Option<String>,
+    ) -> Result<String, JsError> {
+        let plaintext = base64_decode("plaintext", plaintext)?;
+        let associated_data = match associated_data {
+            Some(associated_data) => base64_decode("associatedData", associated_data)?,
+            None => Vec::new(),
+        };
+        Ok(self
+            .seal_bytes(&plaintext, &associated_data)
+            .map(base64_encode)?)
+    }
+
+    /// Returns the base64 encoded plaintext of the next message sealed by the
+    /// other side, throwing for modified, replayed or reordered messages.
+    pub fn open(
+        &mut self,
+        message: String,
+        associated_data: Option<String>,
+    ) -> Result<String, JsError> {
+        let message = base64_decode("message", message)?;
+        let associated_data = match associated_data {
+            Some(associated_data) => base64_decode("associatedData", associated_data)?,
+            None => Vec::new(),
+        };
+        Ok(self
+            .open_bytes(&message, &associated_data)
+            .map(base64_encode)?)
+    }
+}
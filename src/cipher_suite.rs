@@ -1,6 +1,8 @@
-use opaque_ke::ciphersuite::CipherSuite;
+use opaque_ke::{ciphersuite::CipherSuite, errors::ProtocolError};
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
 
-use crate::ksf::CustomKsf;
+use crate::{base64::JsResult, error::Error, ksf::CustomKsf};
 
 pub(crate) struct DefaultCipherSuite;
 
@@ -10,3 +12,124 @@ impl CipherSuite for DefaultCipherSuite {
     type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
     type Ksf = CustomKsf;
 }
+
+pub(crate) struct P256CipherSuite;
+
+impl CipherSuite for P256CipherSuite {
+    type OprfCs = p256::NistP256;
+    type KeGroup = p256::NistP256;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = CustomKsf;
+}
+
+pub(crate) struct P384CipherSuite;
+
+impl CipherSuite for P384CipherSuite {
+    type OprfCs = p384::NistP384;
+    type KeGroup = p384::NistP384;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = CustomKsf;
+}
+
+pub(crate) struct P521CipherSuite;
+
+impl CipherSuite for P521CipherSuite {
+    type OprfCs = p521::NistP521;
+    type KeGroup = p521::NistP521;
+    type KeyExchange = opaque_ke::key_exchange::tripledh::TripleDh;
+    type Ksf = CustomKsf;
+}
+
+/// Selects the cipher suite of an operation. All messages, states and the
+/// server setup of a single registration/login flow must use the same suite,
+/// otherwise deserializing them fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub enum CipherSuiteConfig {
+    #[default]
+    #[serde(rename = "ristretto255")]
+    Ristretto255,
+    #[serde(rename = "p256")]
+    P256,
+    #[serde(rename = "p384")]
+    P384,
+    #[serde(rename = "p521")]
+    P521,
+    /// Ristretto255 with a hybrid key exchange combining 3DH with ML-KEM-768.
+    #[serde(rename = "ristretto255-ml-kem-768")]
+    Ristretto255MlKem768,
+}
+
+impl CipherSuiteConfig {
+    /// Whether the login messages carry an additional ML-KEM-768 exchange.
+    /// Registration is unaffected and shares its records with the classical suite.
+    pub(crate) fn uses_ml_kem(self) -> bool {
+        matches!(self, CipherSuiteConfig::Ristretto255MlKem768)
+    }
+}
+
+/// Evaluates `$body` with `$cs` bound to the cipher suite selected by
+/// `$config` (an `Option<CipherSuiteConfig>`, defaulting to Ristretto255).
+macro_rules! with_cipher_suite {
+    ($config:expr, $cs:ident => $body:block) => {
+        match $config.unwrap_or_default() {
+            $crate::cipher_suite::CipherSuiteConfig::Ristretto255
+            | $crate::cipher_suite::CipherSuiteConfig::Ristretto255MlKem768 => {
+                type $cs = $crate::cipher_suite::DefaultCipherSuite;
+                $body
+            }
+            $crate::cipher_suite::CipherSuiteConfig::P256 => {
+                type $cs = $crate::cipher_suite::P256CipherSuite;
+                $body
+            }
+            $crate::cipher_suite::CipherSuiteConfig::P384 => {
+                type $cs = $crate::cipher_suite::P384CipherSuite;
+                $body
+            }
+            $crate::cipher_suite::CipherSuiteConfig::P521 => {
+                type $cs = $crate::cipher_suite::P521CipherSuite;
+                $body
+            }
+        }
+    };
+}
+
+pub(crate) use with_cipher_suite;
+
+/// Length of the message `$len` (e.g. `RegistrationRequestLen`) in each of
+/// the supported cipher suites.
+macro_rules! message_lens {
+    ($len:ident) => {
+        [
+            $len::<$crate::cipher_suite::DefaultCipherSuite>::USIZE,
+            $len::<$crate::cipher_suite::P256CipherSuite>::USIZE,
+            $len::<$crate::cipher_suite::P384CipherSuite>::USIZE,
+            $len::<$crate::cipher_suite::P521CipherSuite>::USIZE,
+        ]
+    };
+}
+
+pub(crate) use message_lens;
+
+/// opaque-ke ignores trailing bytes of some messages, so a message of a suite
+/// with larger elements could be parsed with another suite. Checking the exact
+/// length rejects such mixed messages. A length of another suite's message
+/// (`suite_lens`) is reported as a cipher suite mismatch, any other length as
+/// a malformed message.
+pub(crate) fn check_message_len(
+    context: &'static str,
+    bytes: &[u8],
+    len: usize,
+    suite_lens: [usize; 4],
+) -> JsResult<()> {
+    if bytes.len() == len {
+        return Ok(());
+    }
+    if suite_lens.contains(&bytes.len()) {
+        return Err(Error::CipherSuiteMismatch { context });
+    }
+    Err(Error::Protocol {
+        context,
+        error: ProtocolError::SerializationError,
+    })
+}
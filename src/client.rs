@@ -1,24 +1,38 @@
+use generic_array::typenum::Unsigned;
+use opaque_ke::errors::{InternalError, ProtocolError};
 use opaque_ke::rand::rngs::OsRng;
 use opaque_ke::{
     ClientLogin, ClientLoginFinishParameters, ClientRegistration,
-    ClientRegistrationFinishParameters, CredentialResponse, RegistrationResponse,
+    ClientRegistrationFinishParameters, CredentialResponse, CredentialResponseLen,
+    RegistrationResponse, RegistrationResponseLen,
 };
 use serde::{Deserialize, Serialize};
 use tsify::Tsify;
 use wasm_bindgen::prelude::*;
+use zeroize::Zeroizing;
 
 use crate::{
-    base64::{base64_decode, base64_encode},
-    cipher_suite::DefaultCipherSuite,
-    error::from_protocol_error,
+    base64::{base64_decode, base64_encode, JsResult},
+    cipher_suite::{check_message_len, message_lens, with_cipher_suite, CipherSuiteConfig},
+    error::{from_protocol_error, Error},
+    export_key,
     identifiers::{get_identifiers, CustomIdentifiers},
-    ksf::{get_custom_ksf, KeyStretchingFunctionConfig},
+    kem,
+    ksf::{
+        get_custom_ksf, get_ksf_salt, memory_limit_kib, CustomKsf, KeyStretchingFunctionConfig,
+        KeyStretchingLimits, KsfSaltConfig,
+    },
+    metadata::{self, CredentialMetadata},
+    upgrade,
 };
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct StartClientLoginParams {
     pub(crate) password: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -34,36 +48,131 @@ pub struct StartClientLoginResult {
 pub fn start_client_login(
     params: StartClientLoginParams,
 ) -> Result<StartClientLoginResult, JsError> {
-    let mut client_rng = OsRng;
-    let client_login_start_result =
-        ClientLogin::<DefaultCipherSuite>::start(&mut client_rng, params.password.as_bytes())
-            .map_err(from_protocol_error("start client login"))?;
+    let (state, message) = start_login(&params)?;
 
     let result = StartClientLoginResult {
-        client_login_state: base64_encode(client_login_start_result.state.serialize()),
-        start_login_request: base64_encode(client_login_start_result.message.serialize()),
+        client_login_state: base64_encode(&*state),
+        start_login_request: base64_encode(message),
     };
     Ok(result)
 }
 
+/// Returns the serialized client login state and the login request.
+pub(crate) fn start_login(
+    params: &StartClientLoginParams,
+) -> JsResult<(Zeroizing<Vec<u8>>, Vec<u8>)> {
+    let mut client_rng = OsRng;
+    let uses_ml_kem = params.cipher_suite.unwrap_or_default().uses_ml_kem();
+    let (mut state, mut message) = with_cipher_suite!(params.cipher_suite, CS => {
+        let client_login_start_result =
+            ClientLogin::<CS>::start(&mut client_rng, params.password.as_bytes())
+                .map_err(from_protocol_error("start client login"))?;
+        let serialized_state = Zeroizing::new(client_login_start_result.state.serialize());
+        // Reserved up front, as growing the state would leave copies behind
+        let seed_len = if uses_ml_kem { kem::SEED_LEN } else { 0 };
+        let mut state = Zeroizing::new(Vec::with_capacity(serialized_state.len() + seed_len));
+        state.extend_from_slice(&serialized_state);
+        (state, client_login_start_result.message.serialize().to_vec())
+    });
+
+    if uses_ml_kem {
+        let seed = Zeroizing::new(kem::generate_seed(&mut client_rng));
+        message.extend(kem::encapsulation_key(&seed));
+        state.extend_from_slice(&seed);
+    }
+    Ok((state, message))
+}
+
 #[derive(Debug, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct FinishClientLoginParams {
     #[serde(rename = "clientLoginState")]
     pub(crate) client_login_state: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
+    // Not part of the flattened parameters, as flattening buffers the values
+    // in a form that cannot hold the function of a callback
+    #[tsify(optional)]
+    #[serde(rename = "keyStretching")]
+    pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[serde(flatten)]
+    pub(crate) shared: FinishClientLoginSharedParams,
+}
+
+/// Parameters of both `finishClientLogin` and `ClientLoginSession.finish`
+#[derive(Debug, Default, Serialize, Deserialize, Tsify)]
+pub struct FinishClientLoginSharedParams {
     #[serde(rename = "loginResponse")]
     pub(crate) login_response: String,
     pub(crate) password: String,
     #[tsify(optional)]
     pub(crate) identifiers: Option<CustomIdentifiers>,
     #[tsify(optional)]
-    #[serde(rename = "keyStretching")]
-    pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[serde(rename = "ksfSalt")]
+    pub(crate) ksf_salt: Option<KsfSaltConfig>,
+    /// Base64 encoded application-wide secret mixed into argon2 key stretching
+    #[tsify(optional)]
+    pub(crate) pepper: Option<String>,
+    /// Upper bound of the key stretching memory in KiB, in addition to the
+    /// memory the wasm instance can address
+    #[tsify(optional)]
+    #[serde(rename = "maxMemoryKiB")]
+    pub(crate) max_memory_kib: Option<u32>,
+    /// Fails the finish step unless the server static public key matches
+    #[tsify(optional)]
+    #[serde(rename = "expectedServerPublicKey")]
+    pub(crate) expected_server_public_key: Option<ServerPublicKeyPin>,
+    /// Metadata returned by `startServerLogin`. Replaces `keyStretching` (except
+    /// for callbacks) and `ksfSalt` with the values used at registration.
+    /// It is only authenticated once the login succeeds, so its key stretching
+    /// function is checked against `keyStretchingLimits` and `maxMemoryKiB`.
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+    /// Bounds the key stretching function of `credentialMetadata`
+    #[tsify(optional)]
+    #[serde(rename = "keyStretchingLimits")]
+    pub(crate) key_stretching_limits: Option<KeyStretchingLimits>,
+    /// Has to match the `context` passed to `startServerLogin`
+    #[tsify(optional)]
+    pub(crate) context: Option<String>,
+}
+
+/// Output of `getServerPublicKey`, or a list of them to accept any of them,
+/// e.g. while rotating the server setup
+#[derive(Debug, Clone, Serialize, Deserialize, Tsify)]
+#[serde(untagged)]
+pub enum ServerPublicKeyPin {
+    Key(String),
+    AnyOf(Vec<String>),
+}
+
+/// Fails unless `expected` is unset or contains `server_public_key`.
+fn check_server_public_key(
+    expected: &Option<ServerPublicKeyPin>,
+    server_public_key: &[u8],
+) -> JsResult<()> {
+    let keys = match expected {
+        Some(ServerPublicKeyPin::Key(key)) => std::slice::from_ref(key),
+        Some(ServerPublicKeyPin::AnyOf(keys)) => keys.as_slice(),
+        None => return Ok(()),
+    };
+    for key in keys {
+        if base64_decode("expectedServerPublicKey", key)? == server_public_key {
+            return Ok(());
+        }
+    }
+    Err(Error::ServerPublicKeyMismatch {
+        context: "expectedServerPublicKey",
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
-pub struct FinishClientLoginResult {
+pub struct FinishClientLoginSuccess {
+    #[tsify(type = "true")]
+    pub(crate) ok: bool,
     #[serde(rename = "finishLoginRequest")]
     pub(crate) finish_login_request: String,
     #[serde(rename = "sessionKey")]
@@ -74,48 +183,243 @@ pub struct FinishClientLoginResult {
     pub(crate) server_static_public_key: String,
 }
 
+/// Why a login failed without throwing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub enum LoginFailureReason {
+    /// Wrong password, the server does not hold the matching registration
+    /// record or server setup, or the server used a different `context`
+    #[serde(rename = "invalid-credentials")]
+    InvalidCredentials,
+    /// The credential metadata was not created at this registration
+    #[serde(rename = "invalid-credential-metadata")]
+    InvalidCredentialMetadata,
+    /// The login response is not valid base64 or not a valid protocol message
+    /// of the cipher suite
+    #[serde(rename = "malformed-response")]
+    MalformedResponse,
+    /// The key stretching function failed, e.g. a throwing callback
+    #[serde(rename = "key-stretching-failed")]
+    KeyStretchingFailed,
+    #[serde(rename = "internal-error")]
+    InternalError,
+}
+
+impl From<&ProtocolError> for LoginFailureReason {
+    fn from(error: &ProtocolError) -> Self {
+        match error {
+            ProtocolError::InvalidLoginError => LoginFailureReason::InvalidCredentials,
+            ProtocolError::SerializationError
+            | ProtocolError::IdentityGroupElementError
+            | ProtocolError::ReflectedValueError => LoginFailureReason::MalformedResponse,
+            ProtocolError::LibraryError(InternalError::KsfError) => {
+                LoginFailureReason::KeyStretchingFailed
+            }
+            ProtocolError::LibraryError(_) => LoginFailureReason::InternalError,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct FinishClientLoginFailure {
+    #[tsify(type = "false")]
+    pub(crate) ok: bool,
+    pub(crate) reason: LoginFailureReason,
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+#[serde(untagged)]
+pub enum FinishClientLoginResult {
+    Success(FinishClientLoginSuccess),
+    Failure(FinishClientLoginFailure),
+}
+
+impl FinishClientLoginResult {
+    pub(crate) fn failure(reason: LoginFailureReason) -> Self {
+        FinishClientLoginResult::Failure(FinishClientLoginFailure { ok: false, reason })
+    }
+}
+
+fn malformed_response() -> JsResult<FinishClientLoginResult> {
+    Ok(FinishClientLoginResult::failure(
+        LoginFailureReason::MalformedResponse,
+    ))
+}
+
 #[wasm_bindgen(js_name = finishClientLogin)]
 pub fn finish_client_login(
     params: FinishClientLoginParams,
-) -> Result<Option<FinishClientLoginResult>, JsError> {
-    let custom_ksf = get_custom_ksf(params.key_stretching_function_config)?;
+) -> Result<FinishClientLoginResult, JsError> {
+    let custom_ksf = get_login_ksf(
+        &params.shared,
+        params.key_stretching_function_config.clone(),
+        params.cipher_suite,
+    )?;
+    Ok(finish_login(&params, custom_ksf.as_ref())?)
+}
 
-    let credential_response_bytes = base64_decode("loginResponse", params.login_response)?;
-    let state_bytes = base64_decode("clientLoginState", params.client_login_state)?;
-    let state = ClientLogin::<DefaultCipherSuite>::deserialize(&state_bytes)
-        .map_err(from_protocol_error("deserialize clientLoginState"))?;
+/// Returns the key stretching function of a login, taking the credential
+/// metadata into account.
+pub(crate) fn get_login_ksf(
+    params: &FinishClientLoginSharedParams,
+    key_stretching: Option<KeyStretchingFunctionConfig>,
+    cipher_suite: Option<CipherSuiteConfig>,
+) -> JsResult<Option<CustomKsf>> {
+    let (key_stretching, ksf_salt) = match &params.credential_metadata {
+        Some(metadata) => {
+            let bytes = base64_decode("credentialMetadata", metadata)?;
+            let (key_stretching, ksf_salt) = CredentialMetadata::deserialize(&bytes)?
+                .into_login_config(cipher_suite, key_stretching)?;
+            (Some(key_stretching), Some(ksf_salt))
+        }
+        None => (key_stretching, params.ksf_salt.clone()),
+    };
 
-    let finish_params = ClientLoginFinishParameters::new(
-        None,
-        get_identifiers(&params.identifiers),
-        custom_ksf.as_ref(),
-    );
-
-    let result = state.finish(
-        params.password.as_bytes(),
-        CredentialResponse::deserialize(&credential_response_bytes)
-            .map_err(from_protocol_error("deserialize loginResponse"))?,
-        finish_params,
-    );
-
-    if result.is_err() {
-        // Client-detected login failure
-        return Ok(None);
+    let ksf_salt = get_ksf_salt(ksf_salt, &params.identifiers)?;
+    let pepper = match &params.pepper {
+        Some(pepper) => Some(base64_decode("pepper", pepper)?),
+        None => None,
+    };
+    let custom_ksf = get_custom_ksf(key_stretching, ksf_salt, pepper)?;
+    if let Some(ksf) = &custom_ksf {
+        if params.credential_metadata.is_some() {
+            let limits = params.key_stretching_limits.unwrap_or_default();
+            ksf.check_limits("credentialMetadata", &limits)?;
+        }
+        ksf.check_memory(params.max_memory_kib)?;
     }
-    let client_login_finish_result = result.unwrap();
+    Ok(custom_ksf)
+}
 
-    Ok(Some(FinishClientLoginResult {
-        finish_login_request: base64_encode(client_login_finish_result.message.serialize()),
-        session_key: base64_encode(client_login_finish_result.session_key),
-        export_key: base64_encode(client_login_finish_result.export_key),
-        server_static_public_key: base64_encode(client_login_finish_result.server_s_pk.serialize()),
-    }))
+/// Finishes a login with the given key stretching function.
+pub(crate) fn finish_login(
+    params: &FinishClientLoginParams,
+    custom_ksf: Option<&CustomKsf>,
+) -> JsResult<FinishClientLoginResult> {
+    let state_bytes = base64_decode("clientLoginState", &params.client_login_state)?;
+    finish_login_with_state(
+        &params.shared,
+        params.cipher_suite,
+        Zeroizing::new(state_bytes),
+        custom_ksf,
+    )
+}
+
+/// Finishes a login from the serialized client login state.
+pub(crate) fn finish_login_with_state(
+    params: &FinishClientLoginSharedParams,
+    cipher_suite: Option<CipherSuiteConfig>,
+    mut state_bytes: Zeroizing<Vec<u8>>,
+    custom_ksf: Option<&CustomKsf>,
+) -> JsResult<FinishClientLoginResult> {
+    let credential_metadata = match &params.credential_metadata {
+        Some(metadata) => Some(base64_decode("credentialMetadata", metadata)?),
+        None => None,
+    };
+    let mut credential_response_bytes = match base64_decode("loginResponse", &params.login_response)
+    {
+        Ok(bytes) => bytes,
+        Err(_) => return malformed_response(),
+    };
+    let context = params.context.as_ref().map(String::as_bytes);
+
+    // (binding context, shared secret) of the ML-KEM part of a hybrid login
+    let ml_kem = if cipher_suite.unwrap_or_default().uses_ml_kem() {
+        let seed = Zeroizing::new(kem::split_off(
+            "clientLoginState",
+            &mut state_bytes,
+            kem::SEED_LEN,
+        )?);
+        let ciphertext = match kem::split_off(
+            "loginResponse",
+            &mut credential_response_bytes,
+            kem::CIPHERTEXT_LEN,
+        ) {
+            Ok(ciphertext) => ciphertext,
+            Err(_) => return malformed_response(),
+        };
+        let context = kem::binding_context(&kem::encapsulation_key(&seed), &ciphertext, context);
+        Some((context, kem::decapsulate(&seed, &ciphertext)?))
+    } else {
+        None
+    };
+
+    with_cipher_suite!(cipher_suite, CS => {
+        let state = ClientLogin::<CS>::deserialize(&state_bytes)
+            .map_err(from_protocol_error("deserialize clientLoginState"))?;
+        if check_message_len(
+            "loginResponse",
+            &credential_response_bytes,
+            CredentialResponseLen::<CS>::USIZE,
+            message_lens!(CredentialResponseLen),
+        )
+        .is_err()
+        {
+            return malformed_response();
+        }
+        let credential_response =
+            match CredentialResponse::deserialize(&credential_response_bytes) {
+                Ok(credential_response) => credential_response,
+                Err(_) => return malformed_response(),
+            };
+
+        let finish_params = ClientLoginFinishParameters::new(
+            match &ml_kem {
+                Some((context, _)) => Some(context.as_slice()),
+                None => context,
+            },
+            get_identifiers(&params.identifiers),
+            custom_ksf,
+        );
+
+        let result = state.finish(params.password.as_bytes(), credential_response, finish_params);
+
+        let client_login_finish_result = match result {
+            Ok(result) => result,
+            Err(error) => match custom_ksf.and_then(CustomKsf::memory_error) {
+                Some(error) => return Err(error),
+                // Client-detected login failure
+                None => return Ok(FinishClientLoginResult::failure((&error).into())),
+            },
+        };
+
+        let server_public_key = client_login_finish_result.server_s_pk.serialize();
+        check_server_public_key(&params.expected_server_public_key, &server_public_key)?;
+
+        if let Some(bytes) = &credential_metadata {
+            if !metadata::verify(bytes, &client_login_finish_result.export_key) {
+                return Ok(FinishClientLoginResult::failure(
+                    LoginFailureReason::InvalidCredentialMetadata,
+                ));
+            }
+        }
+
+        let session_key = match &ml_kem {
+            Some((_, shared_secret)) => {
+                kem::combine_session_key(&client_login_finish_result.session_key, shared_secret)
+            }
+            None => client_login_finish_result.session_key.to_vec(),
+        };
+
+        Ok(FinishClientLoginResult::Success(FinishClientLoginSuccess {
+            ok: true,
+            finish_login_request: base64_encode(client_login_finish_result.message.serialize()),
+            session_key: base64_encode(session_key),
+            export_key: base64_encode(client_login_finish_result.export_key),
+            server_static_public_key: base64_encode(server_public_key),
+        }))
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct StartClientRegistrationParams {
     pub(crate) password: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -131,36 +435,74 @@ pub struct StartClientRegistrationResult {
 pub fn start_client_registration(
     params: StartClientRegistrationParams,
 ) -> Result<StartClientRegistrationResult, JsError> {
-    let mut client_rng = OsRng;
-
-    let client_registration_start_result = ClientRegistration::<DefaultCipherSuite>::start(
-        &mut client_rng,
-        params.password.as_bytes(),
-    )
-    .map_err(from_protocol_error("start client registration"))?;
+    let (state, message) = start_registration(&params)?;
 
     let result = StartClientRegistrationResult {
-        client_registration_state: base64_encode(
-            client_registration_start_result.state.serialize(),
-        ),
-        registration_request: base64_encode(client_registration_start_result.message.serialize()),
+        client_registration_state: base64_encode(state),
+        registration_request: base64_encode(message),
     };
     Ok(result)
 }
 
+/// Returns the serialized client registration state and the registration
+/// request.
+pub(crate) fn start_registration(
+    params: &StartClientRegistrationParams,
+) -> JsResult<(Vec<u8>, Vec<u8>)> {
+    let mut client_rng = OsRng;
+
+    with_cipher_suite!(params.cipher_suite, CS => {
+        let client_registration_start_result =
+            ClientRegistration::<CS>::start(&mut client_rng, params.password.as_bytes())
+                .map_err(from_protocol_error("start client registration"))?;
+
+        Ok((
+            client_registration_start_result.state.serialize().to_vec(),
+            client_registration_start_result.message.serialize().to_vec(),
+        ))
+    })
+}
+
 #[derive(Debug, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
 pub struct FinishClientRegistrationParams {
-    pub(crate) password: String,
-    #[serde(rename = "registrationResponse")]
-    pub(crate) registration_response: String,
     #[serde(rename = "clientRegistrationState")]
     pub(crate) client_registration_state: String,
     #[tsify(optional)]
-    pub(crate) identifiers: Option<CustomIdentifiers>,
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
+    // Not part of the flattened parameters, see `FinishClientLoginParams`
     #[tsify(optional)]
     #[serde(rename = "keyStretching")]
     pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[serde(flatten)]
+    pub(crate) shared: FinishClientRegistrationSharedParams,
+}
+
+/// Parameters of both `finishClientRegistration` and
+/// `ClientRegistrationSession.finish`
+#[derive(Debug, Default, Serialize, Deserialize, Tsify)]
+pub struct FinishClientRegistrationSharedParams {
+    pub(crate) password: String,
+    #[serde(rename = "registrationResponse")]
+    pub(crate) registration_response: String,
+    #[tsify(optional)]
+    pub(crate) identifiers: Option<CustomIdentifiers>,
+    #[tsify(optional)]
+    #[serde(rename = "ksfSalt")]
+    pub(crate) ksf_salt: Option<KsfSaltConfig>,
+    /// Base64 encoded application-wide secret mixed into argon2 key stretching
+    #[tsify(optional)]
+    pub(crate) pepper: Option<String>,
+    /// Upper bound of the key stretching memory in KiB, in addition to the
+    /// memory the wasm instance can address
+    #[tsify(optional)]
+    #[serde(rename = "maxMemoryKiB")]
+    pub(crate) max_memory_kib: Option<u32>,
+    /// Fails the finish step unless the server static public key matches
+    #[tsify(optional)]
+    #[serde(rename = "expectedServerPublicKey")]
+    pub(crate) expected_server_public_key: Option<ServerPublicKeyPin>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -172,44 +514,366 @@ pub struct FinishClientRegistrationResult {
     pub(crate) export_key: String,
     #[serde(rename = "serverStaticPublicKey")]
     pub(crate) server_static_public_key: String,
+    /// Has to be stored next to the registration record and passed to `startServerLogin`
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: String,
 }
 
 #[wasm_bindgen(js_name = finishClientRegistration)]
 pub fn finish_client_registration(
     params: FinishClientRegistrationParams,
 ) -> Result<FinishClientRegistrationResult, JsError> {
-    let custom_ksf = get_custom_ksf(params.key_stretching_function_config)?;
+    let custom_ksf = get_registration_ksf(
+        &params.shared,
+        params.key_stretching_function_config.clone(),
+    )?;
+    Ok(finish_registration(&params, custom_ksf.as_ref())?)
+}
+
+pub(crate) fn get_registration_ksf(
+    params: &FinishClientRegistrationSharedParams,
+    key_stretching: Option<KeyStretchingFunctionConfig>,
+) -> JsResult<Option<CustomKsf>> {
+    let ksf_salt = get_ksf_salt(params.ksf_salt.clone(), &params.identifiers)?;
+    let pepper = match &params.pepper {
+        Some(pepper) => Some(base64_decode("pepper", pepper)?),
+        None => None,
+    };
+    let custom_ksf = get_custom_ksf(key_stretching, ksf_salt, pepper)?;
+    if let Some(ksf) = &custom_ksf {
+        ksf.check_memory(params.max_memory_kib)?;
+    }
+    Ok(custom_ksf)
+}
+
+/// Finishes a registration with the given key stretching function.
+pub(crate) fn finish_registration(
+    params: &FinishClientRegistrationParams,
+    custom_ksf: Option<&CustomKsf>,
+) -> JsResult<FinishClientRegistrationResult> {
+    let client_registration =
+        base64_decode("clientRegistrationState", &params.client_registration_state)?;
+    finish_registration_with_state(
+        &params.shared,
+        &params.key_stretching_function_config,
+        params.cipher_suite,
+        Zeroizing::new(client_registration),
+        custom_ksf,
+    )
+}
 
+/// Finishes a registration from the serialized client registration state.
+pub(crate) fn finish_registration_with_state(
+    params: &FinishClientRegistrationSharedParams,
+    key_stretching: &Option<KeyStretchingFunctionConfig>,
+    cipher_suite: Option<CipherSuiteConfig>,
+    client_registration: Zeroizing<Vec<u8>>,
+    custom_ksf: Option<&CustomKsf>,
+) -> JsResult<FinishClientRegistrationResult> {
+    let credential_metadata =
+        CredentialMetadata::new(cipher_suite, key_stretching, &params.ksf_salt);
     let registration_response_bytes =
-        base64_decode("registrationResponse", params.registration_response)?;
+        base64_decode("registrationResponse", &params.registration_response)?;
     let mut rng: OsRng = OsRng;
-    let client_registration =
-        base64_decode("clientRegistrationState", params.client_registration_state)?;
-    let state = ClientRegistration::<DefaultCipherSuite>::deserialize(&client_registration)
-        .map_err(from_protocol_error("deserialize clientRegistrationState"))?;
-
-    let finish_params = ClientRegistrationFinishParameters::new(
-        get_identifiers(&params.identifiers),
-        custom_ksf.as_ref(),
-    );
-
-    let client_finish_registration_result = state
-        .finish(
-            &mut rng,
-            params.password.as_bytes(),
-            RegistrationResponse::deserialize(&registration_response_bytes)
-                .map_err(from_protocol_error("deserialize registrationResponse"))?,
-            finish_params,
-        )
-        .map_err(from_protocol_error("finish client registration"))?;
-
-    let registration_record_bytes = client_finish_registration_result.message.serialize();
-    let result = FinishClientRegistrationResult {
-        registration_record: base64_encode(registration_record_bytes),
-        export_key: base64_encode(client_finish_registration_result.export_key),
-        server_static_public_key: base64_encode(
-            client_finish_registration_result.server_s_pk.serialize(),
-        ),
+
+    with_cipher_suite!(cipher_suite, CS => {
+        check_message_len(
+            "registrationResponse",
+            &registration_response_bytes,
+            RegistrationResponseLen::<CS>::USIZE,
+            message_lens!(RegistrationResponseLen),
+        )?;
+        let state = ClientRegistration::<CS>::deserialize(&client_registration)
+            .map_err(from_protocol_error("deserialize clientRegistrationState"))?;
+
+        let finish_params = ClientRegistrationFinishParameters::new(
+            get_identifiers(&params.identifiers),
+            custom_ksf,
+        );
+
+        let client_finish_registration_result = state
+            .finish(
+                &mut rng,
+                params.password.as_bytes(),
+                RegistrationResponse::deserialize(&registration_response_bytes)
+                    .map_err(from_protocol_error("deserialize registrationResponse"))?,
+                finish_params,
+            )
+            .map_err(|error| {
+                custom_ksf
+                    .and_then(CustomKsf::memory_error)
+                    .unwrap_or_else(|| from_protocol_error("finish client registration")(error))
+            })?;
+        let server_public_key = client_finish_registration_result.server_s_pk.serialize();
+        check_server_public_key(&params.expected_server_public_key, &server_public_key)?;
+
+        let registration_record_bytes = client_finish_registration_result.message.serialize();
+        let credential_metadata_bytes =
+            credential_metadata.serialize(&client_finish_registration_result.export_key)?;
+        let result = FinishClientRegistrationResult {
+            registration_record: base64_encode(registration_record_bytes),
+            export_key: base64_encode(client_finish_registration_result.export_key),
+            server_static_public_key: base64_encode(server_public_key),
+            credential_metadata: base64_encode(credential_metadata_bytes),
+        };
+        Ok(result)
+    })
+}
+
+/// Authenticates a registration record created with `finishClientRegistration`
+/// after a successful login, e.g. to move a user to new key stretching
+/// parameters. The server accepts it with `verifyRegistrationUpgrade`.
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct AuthenticateRegistrationUpgradeParams {
+    #[serde(rename = "sessionKey")]
+    pub(crate) session_key: String,
+    #[serde(rename = "registrationRecord")]
+    pub(crate) registration_record: String,
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct AuthenticateRegistrationUpgradeResult {
+    #[serde(rename = "upgradeTag")]
+    pub(crate) upgrade_tag: String,
+}
+
+#[wasm_bindgen(js_name = authenticateRegistrationUpgrade)]
+pub fn authenticate_registration_upgrade(
+    params: AuthenticateRegistrationUpgradeParams,
+) -> Result<AuthenticateRegistrationUpgradeResult, JsError> {
+    Ok(AuthenticateRegistrationUpgradeResult {
+        upgrade_tag: record_tag(
+            upgrade::STR_UPGRADE_KEY,
+            params.session_key,
+            params.registration_record,
+            params.credential_metadata,
+        )?,
+    })
+}
+
+/// Authenticates the registration record of a new password, created with
+/// `startClientRegistration` and `finishClientRegistration`, with the session
+/// key of a fresh login using the old password. The server accepts it with
+/// `verifyPasswordChange`.
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct AuthenticatePasswordChangeParams {
+    #[serde(rename = "sessionKey")]
+    pub(crate) session_key: String,
+    #[serde(rename = "registrationRecord")]
+    pub(crate) registration_record: String,
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct AuthenticatePasswordChangeResult {
+    #[serde(rename = "passwordChangeTag")]
+    pub(crate) password_change_tag: String,
+}
+
+#[wasm_bindgen(js_name = authenticatePasswordChange)]
+pub fn authenticate_password_change(
+    params: AuthenticatePasswordChangeParams,
+) -> Result<AuthenticatePasswordChangeResult, JsError> {
+    Ok(AuthenticatePasswordChangeResult {
+        password_change_tag: record_tag(
+            upgrade::STR_PASSWORD_CHANGE_KEY,
+            params.session_key,
+            params.registration_record,
+            params.credential_metadata,
+        )?,
+    })
+}
+
+fn record_tag(
+    label: &[u8],
+    session_key: String,
+    registration_record: String,
+    credential_metadata: Option<String>,
+) -> JsResult<String> {
+    let session_key = base64_decode("sessionKey", session_key)?;
+    let registration_record = base64_decode("registrationRecord", registration_record)?;
+    let credential_metadata = match credential_metadata {
+        Some(metadata) => base64_decode("credentialMetadata", metadata)?,
+        None => Vec::new(),
     };
-    Ok(result)
+
+    Ok(base64_encode(upgrade::tag(
+        label,
+        &session_key,
+        &registration_record,
+        &credential_metadata,
+    )))
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct CalibrateKeyStretchingParams {
+    #[serde(rename = "targetMillis")]
+    pub(crate) target_millis: f64,
+    /// Upper bound of the Argon2id memory in KiB, defaults to 2^16 (64 MiB)
+    #[tsify(optional)]
+    #[serde(rename = "maxMemoryKiB")]
+    pub(crate) max_memory_kib: Option<u32>,
+}
+
+#[cfg(feature = "argon2")]
+#[wasm_bindgen(js_name = calibrateKeyStretching)]
+pub fn calibrate_key_stretching(
+    params: CalibrateKeyStretchingParams,
+) -> Result<KeyStretchingFunctionConfig, JsError> {
+    let max_memory = params.max_memory_kib.unwrap_or(u32::pow(2, 16));
+    Ok(crate::ksf::calibrate_argon2(
+        params.target_millis,
+        max_memory,
+    )?)
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct EstimateKeyStretchingCostParams {
+    #[tsify(optional)]
+    #[serde(rename = "keyStretching")]
+    pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[tsify(optional)]
+    #[serde(rename = "maxMemoryKiB")]
+    pub(crate) max_memory_kib: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct KeyStretchingCost {
+    /// Memory in KiB allocated while stretching the password
+    #[serde(rename = "memoryKiB")]
+    pub(crate) memory_kib: u64,
+    /// Memory in KiB the wasm instance can address, capped by `maxMemoryKiB`
+    #[serde(rename = "memoryLimitKiB")]
+    pub(crate) memory_limit_kib: u64,
+    /// Whether `finishClientLogin` and `finishClientRegistration` will accept
+    /// the configuration with the same `maxMemoryKiB`
+    #[serde(rename = "withinLimit")]
+    pub(crate) within_limit: bool,
+}
+
+#[wasm_bindgen(js_name = estimateKeyStretchingCost)]
+pub fn estimate_key_stretching_cost(
+    params: EstimateKeyStretchingCostParams,
+) -> Result<KeyStretchingCost, JsError> {
+    Ok(estimate_cost(params)?)
+}
+
+pub(crate) fn estimate_cost(
+    params: EstimateKeyStretchingCostParams,
+) -> JsResult<KeyStretchingCost> {
+    let custom_ksf = get_custom_ksf(
+        params.key_stretching_function_config,
+        get_ksf_salt(None, &None)?,
+        None,
+    )?;
+    let memory_kib = custom_ksf.as_ref().map_or(0, CustomKsf::memory_kib);
+    let memory_limit_kib = memory_limit_kib(params.max_memory_kib);
+    Ok(KeyStretchingCost {
+        memory_kib,
+        memory_limit_kib,
+        within_limit: memory_kib <= memory_limit_kib,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct DeriveKeyFromExportKeyParams {
+    #[serde(rename = "exportKey")]
+    pub(crate) export_key: String,
+    /// Purpose of the key, e.g. `"vault-encryption"`
+    pub(crate) label: String,
+    /// Length of the key in bytes, at most 16320
+    pub(crate) length: u32,
+    /// Further binds the key, e.g. to a record or device id
+    #[tsify(optional)]
+    pub(crate) context: Option<String>,
+}
+
+/// Returns the base64 encoded key derived from the export key with
+/// HKDF-SHA512, the same for the same `label`, `length` and `context`.
+#[wasm_bindgen(js_name = deriveKeyFromExportKey)]
+pub fn derive_key_from_export_key(params: DeriveKeyFromExportKeyParams) -> Result<String, JsError> {
+    Ok(derive_key(params).map(base64_encode)?)
+}
+
+pub(crate) fn derive_key(params: DeriveKeyFromExportKeyParams) -> JsResult<Vec<u8>> {
+    let export_key = base64_decode("exportKey", params.export_key)?;
+    export_key::derive_key(
+        &export_key,
+        params.label.as_bytes(),
+        params.context.as_deref().unwrap_or_default().as_bytes(),
+        params.length as usize,
+    )
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct EncryptWithExportKeyParams {
+    #[serde(rename = "exportKey")]
+    pub(crate) export_key: String,
+    /// Base64 encoded data to encrypt
+    pub(crate) plaintext: String,
+    /// Base64 encoded data that is authenticated but not encrypted, has to be
+    /// passed to `decryptWithExportKey` again
+    #[tsify(optional)]
+    #[serde(rename = "associatedData")]
+    pub(crate) associated_data: Option<String>,
+}
+
+/// Encrypts with XChaCha20-Poly1305 under a key derived from the export key
+/// and returns the base64 encoded, versioned ciphertext.
+#[wasm_bindgen(js_name = encryptWithExportKey)]
+pub fn encrypt_with_export_key(params: EncryptWithExportKeyParams) -> Result<String, JsError> {
+    Ok(encrypt(params).map(base64_encode)?)
+}
+
+pub(crate) fn encrypt(params: EncryptWithExportKeyParams) -> JsResult<Vec<u8>> {
+    let export_key = base64_decode("exportKey", params.export_key)?;
+    let plaintext = base64_decode("plaintext", params.plaintext)?;
+    let associated_data = match params.associated_data {
+        Some(associated_data) => base64_decode("associatedData", associated_data)?,
+        None => Vec::new(),
+    };
+    export_key::encrypt(&export_key, &plaintext, &associated_data)
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct DecryptWithExportKeyParams {
+    #[serde(rename = "exportKey")]
+    pub(crate) export_key: String,
+    /// Output of `encryptWithExportKey`
+    pub(crate) ciphertext: String,
+    #[tsify(optional)]
+    #[serde(rename = "associatedData")]
+    pub(crate) associated_data: Option<String>,
+}
+
+/// Returns the base64 encoded plaintext of a ciphertext created with
+/// `encryptWithExportKey`.
+#[wasm_bindgen(js_name = decryptWithExportKey)]
+pub fn decrypt_with_export_key(params: DecryptWithExportKeyParams) -> Result<String, JsError> {
+    Ok(decrypt(params).map(base64_encode)?)
+}
+
+pub(crate) fn decrypt(params: DecryptWithExportKeyParams) -> JsResult<Vec<u8>> {
+    let export_key = base64_decode("exportKey", params.export_key)?;
+    let ciphertext = base64_decode("ciphertext", params.ciphertext)?;
+    let associated_data = match params.associated_data {
+        Some(associated_data) => base64_decode("associatedData", associated_data)?,
+        None => Vec::new(),
+    };
+    export_key::decrypt(&export_key, &ciphertext, &associated_data)
 }
@@ -1,7 +1,40 @@
 use base64::DecodeError;
 use opaque_ke::errors::{InternalError, ProtocolError};
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
 use wasm_bindgen::prelude::*;
 
+#[wasm_bindgen(typescript_custom_section)]
+const OPAQUE_ERROR: &'static str = r#"
+/** Thrown by all exported functions */
+export interface OpaqueError extends Error {
+    code: ErrorCode;
+    context: string;
+}
+"#;
+
+/// Stable `code` of the thrown errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    InvalidBase64,
+    DeserializationFailed,
+    CipherSuiteMismatch,
+    InvalidLogin,
+    ServerPublicKeyMismatch,
+    ProtocolError,
+    KsfInvalidParams,
+    KsfFailed,
+    KsfMemoryLimit,
+    KsfCostLimit,
+    InvalidState,
+    InvalidParameter,
+    DecryptionFailed,
+    MessageOutOfOrder,
+    InternalError,
+}
+
 pub(crate) enum Error {
     Protocol {
         context: &'static str,
@@ -15,6 +48,37 @@ pub(crate) enum Error {
         context: &'static str,
         error: InternalError,
     },
+    CipherSuiteMismatch {
+        context: &'static str,
+    },
+    InvalidState {
+        context: &'static str,
+    },
+    InvalidParameter {
+        context: &'static str,
+    },
+    DecryptionFailed {
+        context: &'static str,
+    },
+    ServerPublicKeyMismatch {
+        context: &'static str,
+    },
+    /// The key stretching function failed while running, as opposed to
+    /// invalid parameters rejected before it runs
+    KsfFailed {
+        context: &'static str,
+    },
+    KsfMemoryLimit {
+        required_kib: u64,
+        limit_kib: u64,
+    },
+    KsfCostLimit {
+        context: &'static str,
+    },
+    MessageOutOfOrder {
+        expected: u64,
+        received: u64,
+    },
 }
 
 pub(crate) fn from_base64_error(context: &'static str) -> impl Fn(DecodeError) -> Error {
@@ -25,8 +89,64 @@ pub(crate) fn from_protocol_error(context: &'static str) -> impl Fn(ProtocolErro
     move |error| Error::Protocol { context, error }
 }
 
+impl Error {
+    pub(crate) fn code(&self) -> ErrorCode {
+        match self {
+            Error::Protocol { error, .. } => match error {
+                ProtocolError::InvalidLoginError => ErrorCode::InvalidLogin,
+                ProtocolError::SerializationError | ProtocolError::IdentityGroupElementError => {
+                    ErrorCode::DeserializationFailed
+                }
+                ProtocolError::LibraryError(InternalError::KsfError) => ErrorCode::KsfFailed,
+                ProtocolError::LibraryError(error) => internal_error_code(error),
+                ProtocolError::ReflectedValueError => ErrorCode::ProtocolError,
+            },
+            Error::Base64 { .. } => ErrorCode::InvalidBase64,
+            Error::Internal { error, .. } => internal_error_code(error),
+            Error::CipherSuiteMismatch { .. } => ErrorCode::CipherSuiteMismatch,
+            Error::InvalidState { .. } => ErrorCode::InvalidState,
+            Error::InvalidParameter { .. } => ErrorCode::InvalidParameter,
+            Error::DecryptionFailed { .. } => ErrorCode::DecryptionFailed,
+            Error::ServerPublicKeyMismatch { .. } => ErrorCode::ServerPublicKeyMismatch,
+            Error::KsfFailed { .. } => ErrorCode::KsfFailed,
+            Error::KsfMemoryLimit { .. } => ErrorCode::KsfMemoryLimit,
+            Error::KsfCostLimit { .. } => ErrorCode::KsfCostLimit,
+            Error::MessageOutOfOrder { .. } => ErrorCode::MessageOutOfOrder,
+        }
+    }
+
+    pub(crate) fn context(&self) -> &'static str {
+        match self {
+            Error::Protocol { context, .. }
+            | Error::Base64 { context, .. }
+            | Error::Internal { context, .. }
+            | Error::CipherSuiteMismatch { context }
+            | Error::InvalidState { context }
+            | Error::InvalidParameter { context }
+            | Error::DecryptionFailed { context }
+            | Error::ServerPublicKeyMismatch { context }
+            | Error::KsfFailed { context }
+            | Error::KsfCostLimit { context } => context,
+            Error::KsfMemoryLimit { .. } => "keyStretching",
+            Error::MessageOutOfOrder { .. } => "secureChannel open",
+        }
+    }
+}
+
+fn internal_error_code(error: &InternalError) -> ErrorCode {
+    match error {
+        InternalError::KsfError => ErrorCode::KsfInvalidParams,
+        InternalError::InvalidByteSequence
+        | InternalError::SizeError { .. }
+        | InternalError::PointError => ErrorCode::DeserializationFailed,
+        _ => ErrorCode::InternalError,
+    }
+}
+
 impl From<Error> for JsError {
     fn from(err: Error) -> Self {
+        let code = err.code();
+        let context = err.context();
         let msg = match err {
             Error::Protocol { context, error } => {
                 format!("Opaque protocol error at \"{}\"; {}", context, error)
@@ -37,7 +157,45 @@ impl From<Error> for JsError {
             Error::Internal { context, error } => {
                 format!("Internal error at \"{}\"; {}", context, error)
             }
+            Error::CipherSuiteMismatch { context } => {
+                format!("\"{}\" does not match the selected cipher suite", context)
+            }
+            Error::InvalidState { context } | Error::InvalidParameter { context } => {
+                context.to_string()
+            }
+            Error::DecryptionFailed { context } => {
+                format!(
+                    "Decryption failed at \"{}\"; wrong key or modified data",
+                    context
+                )
+            }
+            Error::ServerPublicKeyMismatch { context } => {
+                format!("server static public key does not match \"{}\"", context)
+            }
+            Error::KsfFailed { context } => format!("keyStretching failed at \"{}\"", context),
+            Error::KsfMemoryLimit {
+                required_kib,
+                limit_kib,
+            } => format!(
+                "keyStretching needs {} KiB of memory, exceeding the limit of {} KiB",
+                required_kib, limit_kib
+            ),
+            Error::KsfCostLimit { context } => format!(
+                "keyStretching of \"{}\" exceeds keyStretchingLimits",
+                context
+            ),
+            Error::MessageOutOfOrder { expected, received } => format!(
+                "secureChannel expected message {} but received message {}",
+                expected, received
+            ),
         };
-        JsError::new(&msg)
+
+        // JsError is a handle to the JavaScript `Error`, so setting the
+        // properties on a clone of it modifies the thrown object
+        let js_error = JsError::new(&msg);
+        let value = JsValue::from(js_error.clone());
+        let _ = js_sys::Reflect::set(&value, &"code".into(), &code.into());
+        let _ = js_sys::Reflect::set(&value, &"context".into(), &context.into());
+        js_error
     }
 }
@@ -0,0 +1,124 @@
+use std::convert::TryInto;
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+use hkdf::Hkdf;
+use opaque_ke::rand::rngs::OsRng;
+use sha2::Sha512;
+
+use crate::{base64::JsResult, error::Error};
+
+/// HKDF-SHA512 can expand to at most 255 hash lengths.
+const MAX_KEY_LEN: usize = 255 * 64;
+const STR_DERIVE_KEY: &[u8] = b"OPAQUE-Export-Key-Derivation";
+const STR_ENCRYPTION_KEY: &[u8] = b"OPAQUE-Export-Key-Encryption";
+
+const VERSION: u8 = 1;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+/// Derives a subkey of `length` bytes from the export key with HKDF-SHA512.
+/// The info is `STR_DERIVE_KEY || len(label) || label || len(context) ||
+/// context` with 8 byte big-endian lengths, so different labels and contexts
+/// never yield related keys.
+pub(crate) fn derive_key(
+    export_key: &[u8],
+    label: &[u8],
+    context: &[u8],
+    length: usize,
+) -> JsResult<Vec<u8>> {
+    if label.is_empty() {
+        return Err(Error::InvalidParameter {
+            context: "Invalid label (must not be empty)",
+        });
+    }
+    if length == 0 || length > MAX_KEY_LEN {
+        return Err(Error::InvalidParameter {
+            context: "Invalid length (1 to 16320 bytes are supported)",
+        });
+    }
+
+    let mut info = STR_DERIVE_KEY.to_vec();
+    for bytes in [label, context] {
+        info.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+        info.extend_from_slice(bytes);
+    }
+    let mut key = vec![0; length];
+    Hkdf::<Sha512>::new(None, export_key)
+        .expand(&info, &mut key)
+        .expect("length is a valid HKDF-SHA512 output length");
+    Ok(key)
+}
+
+fn cipher(export_key: &[u8]) -> XChaCha20Poly1305 {
+    let mut key = [0; KEY_LEN];
+    Hkdf::<Sha512>::new(None, export_key)
+        .expand(STR_ENCRYPTION_KEY, &mut key)
+        .expect("key length is a valid HKDF-SHA512 output length");
+    XChaCha20Poly1305::new(&key.into())
+}
+
+/// The version is authenticated together with the associated data.
+fn associated_data(version: u8, associated_data: &[u8]) -> Vec<u8> {
+    [&[version], associated_data].concat()
+}
+
+/// Encrypts with XChaCha20-Poly1305 under a key derived from the export key
+/// and a random nonce. The ciphertext format is `version || nonce ||
+/// ciphertext || tag`.
+pub(crate) fn encrypt(
+    export_key: &[u8],
+    plaintext: &[u8],
+    associated_data_bytes: &[u8],
+) -> JsResult<Vec<u8>> {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher(export_key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: plaintext,
+                aad: &associated_data(VERSION, associated_data_bytes),
+            },
+        )
+        .map_err(|_| Error::InvalidParameter {
+            context: "Invalid plaintext (too long)",
+        })?;
+    Ok([&[VERSION], &nonce[..], &ciphertext].concat())
+}
+
+/// Decrypts a ciphertext of `encrypt`, failing if it or the associated data
+/// was modified or a different export key is used.
+pub(crate) fn decrypt(
+    export_key: &[u8],
+    ciphertext: &[u8],
+    associated_data_bytes: &[u8],
+) -> JsResult<Vec<u8>> {
+    let (version, ciphertext) = match ciphertext.split_first() {
+        Some((&VERSION, ciphertext)) if ciphertext.len() >= NONCE_LEN => (VERSION, ciphertext),
+        Some((&VERSION, _)) => {
+            return Err(Error::InvalidParameter {
+                context: "Invalid ciphertext (too short)",
+            })
+        }
+        _ => {
+            return Err(Error::InvalidParameter {
+                context: "Invalid ciphertext (unsupported version)",
+            })
+        }
+    };
+    let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("nonce has NONCE_LEN bytes");
+    cipher(export_key)
+        .decrypt(
+            &XNonce::from(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: &associated_data(version, associated_data_bytes),
+            },
+        )
+        .map_err(|_| Error::DecryptionFailed {
+            context: "decrypt ciphertext",
+        })
+}
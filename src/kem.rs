@@ -0,0 +1,114 @@
+use std::convert::TryFrom;
+
+use hkdf::Hkdf;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{array::Array, EncodedSizeUser, KemCore, MlKem768, B32};
+use opaque_ke::errors::{InternalError, ProtocolError};
+use rand::{CryptoRng, RngCore};
+use sha2::{Digest, Sha512};
+
+use crate::{base64::JsResult, error::Error};
+
+pub(crate) const SEED_LEN: usize = 64;
+pub(crate) const ENCAPSULATION_KEY_LEN: usize = 1184;
+pub(crate) const CIPHERTEXT_LEN: usize = 1088;
+pub(crate) const SHARED_SECRET_LEN: usize = 32;
+
+const STR_CONTEXT: &[u8] = b"OPAQUE-3DH-ML-KEM-768-Context";
+const STR_SESSION_KEY: &[u8] = b"OPAQUE-3DH-ML-KEM-768-SessionKey";
+
+type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+/// Removes the trailing `len` bytes of a hybrid message or state and returns them.
+pub(crate) fn split_off(
+    context: &'static str,
+    bytes: &mut Vec<u8>,
+    len: usize,
+) -> JsResult<Vec<u8>> {
+    if bytes.len() < len {
+        return Err(Error::Protocol {
+            context,
+            error: ProtocolError::SerializationError,
+        });
+    }
+    Ok(bytes.split_off(bytes.len() - len))
+}
+
+/// Generates the seed the client's ML-KEM key pair is derived from; only the
+/// seed is kept in the client login state.
+pub(crate) fn generate_seed<R: RngCore + CryptoRng>(rng: &mut R) -> Vec<u8> {
+    let mut seed = vec![0; SEED_LEN];
+    rng.fill_bytes(&mut seed);
+    seed
+}
+
+fn key_pair(seed: &[u8]) -> (DecapsulationKey, EncapsulationKey) {
+    let (d, z) = seed.split_at(SEED_LEN / 2);
+    MlKem768::generate_deterministic(&B32::from_fn(|i| d[i]), &B32::from_fn(|i| z[i]))
+}
+
+pub(crate) fn encapsulation_key(seed: &[u8]) -> Vec<u8> {
+    key_pair(seed).1.as_bytes().to_vec()
+}
+
+/// Returns the ciphertext and the shared secret for the client's encapsulation key.
+pub(crate) fn encapsulate<R: RngCore + CryptoRng>(
+    rng: &mut R,
+    encapsulation_key: &[u8],
+) -> JsResult<(Vec<u8>, Vec<u8>)> {
+    let encapsulation_key = Array::try_from(encapsulation_key).map_err(|_| Error::Protocol {
+        context: "deserialize ML-KEM encapsulation key",
+        error: ProtocolError::SerializationError,
+    })?;
+    let (ciphertext, shared_secret) = EncapsulationKey::from_bytes(&encapsulation_key)
+        .encapsulate(rng)
+        .map_err(|_| Error::Internal {
+            context: "ML-KEM encapsulation",
+            error: InternalError::InvalidByteSequence,
+        })?;
+    Ok((ciphertext.to_vec(), shared_secret.to_vec()))
+}
+
+pub(crate) fn decapsulate(seed: &[u8], ciphertext: &[u8]) -> JsResult<Vec<u8>> {
+    let ciphertext = Array::try_from(ciphertext).map_err(|_| Error::Protocol {
+        context: "deserialize ML-KEM ciphertext",
+        error: ProtocolError::SerializationError,
+    })?;
+    let shared_secret = key_pair(seed)
+        .0
+        .decapsulate(&ciphertext)
+        .map_err(|_| Error::Internal {
+            context: "ML-KEM decapsulation",
+            error: InternalError::InvalidByteSequence,
+        })?;
+    Ok(shared_secret.to_vec())
+}
+
+/// Binds the ML-KEM messages to the 3DH transcript by passing this value as
+/// the OPAQUE `context`, so tampering with them fails the key confirmation.
+/// The application `context` follows the fixed-length hash.
+pub(crate) fn binding_context(
+    encapsulation_key: &[u8],
+    ciphertext: &[u8],
+    context: Option<&[u8]>,
+) -> Vec<u8> {
+    let mut binding_context = Sha512::new()
+        .chain_update(STR_CONTEXT)
+        .chain_update(encapsulation_key)
+        .chain_update(ciphertext)
+        .finalize()
+        .to_vec();
+    binding_context.extend_from_slice(context.unwrap_or_default());
+    binding_context
+}
+
+/// Combines the 3DH session key with the ML-KEM shared secret.
+pub(crate) fn combine_session_key(session_key: &[u8], shared_secret: &[u8]) -> Vec<u8> {
+    let ikm = [session_key, shared_secret].concat();
+    let mut output = vec![0; session_key.len()];
+    Hkdf::<Sha512>::new(None, &ikm)
+        .expand(STR_SESSION_KEY, &mut output)
+        .expect("session key length is a valid HKDF-SHA512 output length");
+    output
+}
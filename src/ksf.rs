@@ -1,18 +1,46 @@
-use argon2::{Algorithm, Argon2, ParamsBuilder, Version};
+#[cfg(feature = "argon2")]
+use argon2::{Algorithm, Argon2, Block, Params, ParamsBuilder, Version};
+#[cfg(feature = "argon2")]
+use generic_array::typenum::U64;
+use std::cell::{Cell, RefCell};
+
 use generic_array::{ArrayLength, GenericArray};
+#[cfg(feature = "argon2")]
+use hkdf::Hkdf;
+use hmac::{digest::KeyInit, Hmac, Mac};
+use js_sys::{Function, Uint8Array};
 use opaque_ke::{errors::InternalError, ksf::Ksf};
+use pbkdf2::pbkdf2_hmac;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
 use tsify::Tsify;
+use wasm_bindgen::{JsCast, JsValue};
+
+#[cfg(feature = "parallel")]
+use crate::parallel;
+#[cfg(feature = "argon2")]
+use crate::{argon2_state::Argon2State, utils::try_vec};
+use crate::{
+    base64::{base64_decode, JsResult},
+    error::Error,
+    identifiers::CustomIdentifiers,
+    utils::{addressable_memory_kib, growable_memory_kib, now_millis},
+};
 
-use crate::error::Error;
+const STR_KSF_SALT: &[u8] = b"OPAQUE-KSF-Salt";
+const DEFAULT_SALT_LEN: usize = 16;
+const MIN_SALT_LEN: usize = 8;
+#[cfg(feature = "argon2")]
+const STR_ARGON2_OUTPUT: &[u8] = b"OPAQUE-KSF-Argon2-Output";
 
-#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Tsify)]
 #[tsify(into_wasm_abi, from_wasm_abi)]
-pub(crate) enum KeyStretchingFunctionConfig {
+pub enum KeyStretchingFunctionConfig {
     #[serde(rename = "rfc-9106-recommended")]
     Rfc9106Recommended,
     #[serde(rename = "libsodium-moderate")]
     LibsodiumModerate,
+    #[default]
     #[serde(rename = "memory-constrained")]
     MemoryConstrained,
     #[serde(rename = "argon2id-custom")]
@@ -24,11 +52,207 @@ pub(crate) enum KeyStretchingFunctionConfig {
         #[serde(rename = "parallelism")]
         parallelism: u32,
     },
+    /// Argon2 with a selectable variant and version, e.g. to migrate records
+    /// of an existing Argon2i or Argon2d based system.
+    #[serde(rename = "argon2-custom")]
+    Argon2Custom {
+        /// Defaults to argon2id
+        #[tsify(optional)]
+        #[serde(rename = "algorithm")]
+        algorithm: Option<Argon2AlgorithmConfig>,
+        /// Defaults to 0x13
+        #[tsify(optional)]
+        #[serde(rename = "version")]
+        version: Option<Argon2VersionConfig>,
+        #[serde(rename = "iterations")]
+        iterations: u32,
+        #[serde(rename = "memory")]
+        memory: u32,
+        #[serde(rename = "parallelism")]
+        parallelism: u32,
+        /// Length of the Argon2 tag, which is expanded with HKDF-SHA512 if it
+        /// differs from the OPRF output length. Defaults to the OPRF output length.
+        #[tsify(optional)]
+        #[serde(rename = "outputLength")]
+        output_length: Option<u32>,
+    },
+    #[serde(rename = "scrypt-interactive")]
+    ScryptInteractive,
+    #[serde(rename = "scrypt-recommended")]
+    ScryptRecommended,
+    #[serde(rename = "scrypt-custom")]
+    ScryptCustom {
+        /// CPU/memory cost, a power of two
+        #[serde(rename = "n")]
+        n: u64,
+        /// Block size
+        #[serde(rename = "r")]
+        r: u32,
+        /// Parallelization
+        #[serde(rename = "p")]
+        p: u32,
+    },
+    #[serde(rename = "pbkdf2-sha256-recommended")]
+    Pbkdf2Sha256Recommended,
+    #[serde(rename = "pbkdf2-sha512-recommended")]
+    Pbkdf2Sha512Recommended,
+    #[serde(rename = "pbkdf2-sha256-custom")]
+    Pbkdf2Sha256Custom {
+        #[serde(rename = "iterations")]
+        iterations: u32,
+    },
+    #[serde(rename = "pbkdf2-sha512-custom")]
+    Pbkdf2Sha512Custom {
+        #[serde(rename = "iterations")]
+        iterations: u32,
+    },
+    /// Skips key stretching, like the identity KSF of the RFC 9807 test vectors.
+    /// Only safe if the password has already been stretched before it is passed in.
+    #[serde(rename = "none")]
+    Identity,
+    /// Delegates key stretching to a synchronous JavaScript function which
+    /// receives the OPRF output and the salt and returns the stretched bytes
+    /// with the same length as the input. Asynchronous functions are not
+    /// supported: a returned Promise fails like any other non-`Uint8Array`
+    /// value. `KeyStretchingTask` runs the built-in functions without blocking.
+    #[serde(rename = "callback")]
+    Callback {
+        #[serde(rename = "fn", with = "serde_wasm_bindgen::preserve")]
+        #[tsify(type = "(input: Uint8Array, salt: Uint8Array) => Uint8Array")]
+        function: Function,
+    },
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Tsify)]
+pub enum Argon2AlgorithmConfig {
+    #[serde(rename = "argon2d")]
+    Argon2d,
+    #[serde(rename = "argon2i")]
+    Argon2i,
+    #[default]
+    #[serde(rename = "argon2id")]
+    Argon2id,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Tsify)]
+pub enum Argon2VersionConfig {
+    #[serde(rename = "0x10")]
+    V0x10,
+    #[default]
+    #[serde(rename = "0x13")]
+    V0x13,
+}
+
+#[cfg(feature = "argon2")]
+impl From<Argon2AlgorithmConfig> for Algorithm {
+    fn from(config: Argon2AlgorithmConfig) -> Self {
+        match config {
+            Argon2AlgorithmConfig::Argon2d => Algorithm::Argon2d,
+            Argon2AlgorithmConfig::Argon2i => Algorithm::Argon2i,
+            Argon2AlgorithmConfig::Argon2id => Algorithm::Argon2id,
+        }
+    }
+}
+
+#[cfg(feature = "argon2")]
+impl From<Argon2VersionConfig> for Version {
+    fn from(config: Argon2VersionConfig) -> Self {
+        match config {
+            Argon2VersionConfig::V0x10 => Version::V0x10,
+            Argon2VersionConfig::V0x13 => Version::V0x13,
+        }
+    }
+}
+
+/// Selects the salt of the key stretching function. It has to be the same for
+/// `finishClientRegistration` and `finishClientLogin`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub(crate) enum KsfSaltConfig {
+    /// All-zero salt, compatible with records created before salts were configurable
+    #[default]
+    #[serde(rename = "zero")]
+    Zero,
+    /// Salt derived from the client and server identifiers
+    #[serde(rename = "identifiers")]
+    Identifiers,
+    /// Explicit base64 encoded salt of at least 8 bytes
+    #[serde(rename = "custom")]
+    Custom(String),
+}
+
+/// Upper bounds for the cost of a key stretching function read from
+/// credential metadata. The metadata is unauthenticated until the login
+/// succeeds, so a malicious server could otherwise make the client stretch
+/// for an unbounded time or allocate all the memory it can address.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, Tsify)]
+pub struct KeyStretchingLimits {
+    /// Defaults to 10 Argon2 iterations
+    #[tsify(optional)]
+    #[serde(rename = "maxArgon2Iterations")]
+    pub(crate) max_argon2_iterations: Option<u32>,
+    /// Upper bound of `n * r * p` of scrypt, defaults to 2^23
+    #[tsify(optional)]
+    #[serde(rename = "maxScryptCost")]
+    pub(crate) max_scrypt_cost: Option<u64>,
+    /// Defaults to 6,000,000 PBKDF2 iterations
+    #[tsify(optional)]
+    #[serde(rename = "maxPbkdf2Iterations")]
+    pub(crate) max_pbkdf2_iterations: Option<u32>,
+    /// Memory in KiB, defaults to the 2^21 - 1 KiB of `rfc-9106-recommended`,
+    /// the largest built-in configuration
+    #[tsify(optional)]
+    #[serde(rename = "maxMemoryKiB")]
+    pub(crate) max_memory_kib: Option<u32>,
+}
+
+enum KsfAlgorithm {
+    #[cfg(feature = "argon2")]
+    Argon2(Algorithm, Version, Params),
+    #[cfg(not(feature = "argon2"))]
+    Unavailable,
+    Scrypt(scrypt::Params),
+    Pbkdf2Sha256(u32),
+    Pbkdf2Sha512(u32),
+    Identity,
+    Callback(Function),
+    /// Records its input and fails, used to obtain the input of a [`KeyStretchingJob`]
+    Capture(RefCell<Option<Vec<u8>>>),
+    /// Returns the output of a finished [`KeyStretchingJob`] for its input
+    Precomputed {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    },
 }
 
-#[derive(Default)]
 pub(crate) struct CustomKsf {
-    argon: Argon2<'static>,
+    algorithm: KsfAlgorithm,
+    salt: Vec<u8>,
+    /// Secret key of Argon2
+    #[cfg_attr(not(feature = "argon2"), allow(dead_code))]
+    pepper: Option<Vec<u8>>,
+    /// Set when the memory of the key stretching function could not be
+    /// allocated, as `Ksf::hash` can only report an `InternalError`
+    out_of_memory: Cell<bool>,
+}
+
+impl Default for CustomKsf {
+    fn default() -> Self {
+        CustomKsf {
+            #[cfg(feature = "argon2")]
+            algorithm: KsfAlgorithm::Argon2(
+                Algorithm::default(),
+                Version::default(),
+                Params::default(),
+            ),
+            // Builds without argon2 have no implicit default
+            #[cfg(not(feature = "argon2"))]
+            algorithm: KsfAlgorithm::Unavailable,
+            salt: vec![0; DEFAULT_SALT_LEN],
+            pepper: None,
+            out_of_memory: Cell::new(false),
+        }
+    }
 }
 
 /// Used for the key stretching function in OPAQUE
@@ -39,53 +263,707 @@ impl Ksf for CustomKsf {
         input: generic_array::GenericArray<u8, L>,
     ) -> Result<GenericArray<u8, L>, InternalError> {
         let mut output = GenericArray::default();
-        self.argon
-            .hash_password_into(&input, &[0; argon2::RECOMMENDED_SALT_LEN], &mut output)
-            .map_err(|_| InternalError::KsfError)?;
+        self.stretch(&input, &mut output)?;
         Ok(output)
     }
 }
 
-fn build_argon2_ksf(
+impl CustomKsf {
+    /// Computes the key stretching function for an input of any length, the
+    /// output has the same length as the input.
+    fn stretch(&self, input: &[u8], output: &mut [u8]) -> Result<(), InternalError> {
+        match &self.algorithm {
+            #[cfg(feature = "argon2")]
+            KsfAlgorithm::Argon2(..) => self.hash_argon2(input, output)?,
+            #[cfg(not(feature = "argon2"))]
+            KsfAlgorithm::Unavailable => return Err(InternalError::KsfError),
+            KsfAlgorithm::Scrypt(params) => {
+                // scrypt allocates its memory itself; reserving it first grows
+                // the wasm memory or fails without aborting, and the allocator
+                // hands the released memory to scrypt
+                Vec::<u8>::new()
+                    .try_reserve_exact(self.memory_kib() as usize * 1024)
+                    .map_err(|_| self.allocation_failed())?;
+                scrypt::scrypt(input, &self.salt, params, output)
+                    .map_err(|_| InternalError::KsfError)?
+            }
+            KsfAlgorithm::Pbkdf2Sha256(iterations) => {
+                pbkdf2_hmac::<Sha256>(input, &self.salt, *iterations, output)
+            }
+            KsfAlgorithm::Pbkdf2Sha512(iterations) => {
+                pbkdf2_hmac::<Sha512>(input, &self.salt, *iterations, output)
+            }
+            KsfAlgorithm::Identity => output.copy_from_slice(input),
+            KsfAlgorithm::Callback(function) => {
+                let stretched = function
+                    .call2(
+                        &JsValue::NULL,
+                        &Uint8Array::from(input),
+                        &Uint8Array::from(&self.salt[..]),
+                    )
+                    .ok()
+                    .and_then(|value| value.dyn_into::<Uint8Array>().ok())
+                    .filter(|stretched| stretched.length() as usize == output.len())
+                    .ok_or(InternalError::KsfError)?;
+                stretched.copy_to(output);
+            }
+            KsfAlgorithm::Capture(captured) => {
+                captured.replace(Some(input.to_vec()));
+                return Err(InternalError::KsfError);
+            }
+            KsfAlgorithm::Precomputed {
+                input: expected_input,
+                output: precomputed,
+            } => {
+                if input != expected_input.as_slice() || output.len() != precomputed.len() {
+                    return Err(InternalError::KsfError);
+                }
+                output.copy_from_slice(precomputed);
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "argon2")]
+    fn hash_argon2(&self, input: &[u8], output: &mut [u8]) -> Result<(), InternalError> {
+        let (algorithm, version, params) = match &self.algorithm {
+            KsfAlgorithm::Argon2(algorithm, version, params) => (*algorithm, *version, params),
+            _ => return Err(InternalError::KsfError),
+        };
+        let hash = |out: &mut [u8]| {
+            let argon = match &self.pepper {
+                Some(pepper) => Argon2::new_with_secret(pepper, algorithm, version, params.clone())
+                    .map_err(|_| InternalError::KsfError)?,
+                None => Argon2::new(algorithm, version, params.clone()),
+            };
+            let memory = try_vec(params.block_count(), Block::default())
+                .map_err(|_| self.allocation_failed())?;
+            // With the `parallel` feature the argon2 crate computes the lanes
+            // on the rayon thread pool, or on the calling thread alone if
+            // `initKeyStretchingThreads` has not started the workers
+            #[cfg(feature = "parallel")]
+            parallel::start_argon2();
+            argon
+                .hash_password_into_with_memory(input, &self.salt, out, memory)
+                .map_err(|_| InternalError::KsfError)
+        };
+        match params.output_len() {
+            Some(len) if len != output.len() => {
+                let mut tag = vec![0; len];
+                hash(&mut tag)?;
+                expand_argon2_tag(&tag, output)
+            }
+            _ => hash(output),
+        }
+    }
+}
+
+/// Expands an Argon2 tag of the configured `outputLength` to the length of
+/// the OPAQUE output.
+#[cfg(feature = "argon2")]
+fn expand_argon2_tag(tag: &[u8], output: &mut [u8]) -> Result<(), InternalError> {
+    Hkdf::<Sha512>::new(None, tag)
+        .expand(STR_ARGON2_OUTPUT, output)
+        .map_err(|_| InternalError::KsfError)
+}
+
+/// Memory in KiB that key stretching may allocate: what the wasm instance can
+/// address, capped by the optional caller-provided ceiling. Whether the memory
+/// is actually available only shows when it is allocated.
+pub(crate) fn memory_limit_kib(max_memory_kib: Option<u32>) -> u64 {
+    match max_memory_kib {
+        Some(max_memory_kib) => addressable_memory_kib().min(max_memory_kib.into()),
+        None => addressable_memory_kib(),
+    }
+}
+
+impl CustomKsf {
+    /// Estimated memory in KiB needed for computing the key stretching function.
+    pub(crate) fn memory_kib(&self) -> u64 {
+        match &self.algorithm {
+            // Argon2 blocks are 1 KiB each
+            #[cfg(feature = "argon2")]
+            KsfAlgorithm::Argon2(_, _, params) => params.block_count() as u64,
+            // 128 * r * (N + p) bytes for the blocks plus 256 * r bytes of scratch space
+            KsfAlgorithm::Scrypt(params) => {
+                let (n, r, p) = (1u64 << params.log_n(), params.r() as u64, params.p() as u64);
+                (128 * r * (n + p) + 256 * r).div_ceil(1024)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Fails before allocating if the key stretching function needs more
+    /// memory than the wasm instance can address or than `max_memory_kib`.
+    pub(crate) fn check_memory(&self, max_memory_kib: Option<u32>) -> JsResult<()> {
+        let required_kib = self.memory_kib();
+        let limit_kib = memory_limit_kib(max_memory_kib);
+        if required_kib > limit_kib {
+            return Err(Error::KsfMemoryLimit {
+                required_kib,
+                limit_kib,
+            });
+        }
+        Ok(())
+    }
+
+    fn allocation_failed(&self) -> InternalError {
+        self.out_of_memory.set(true);
+        InternalError::KsfError
+    }
+
+    /// The error of a key stretching function whose memory could not be
+    /// allocated, which opaque-ke reports as a plain `KsfError`. The limit is
+    /// what the wasm instance could still grow by.
+    pub(crate) fn memory_error(&self) -> Option<Error> {
+        self.out_of_memory.get().then(|| Error::KsfMemoryLimit {
+            required_kib: self.memory_kib(),
+            limit_kib: growable_memory_kib(),
+        })
+    }
+
+    /// Fails before stretching if the key stretching function costs more
+    /// time or memory than `limits` allow.
+    pub(crate) fn check_limits(
+        &self,
+        context: &'static str,
+        limits: &KeyStretchingLimits,
+    ) -> JsResult<()> {
+        let exceeded = match &self.algorithm {
+            #[cfg(feature = "argon2")]
+            KsfAlgorithm::Argon2(_, _, params) => {
+                params.t_cost() > limits.max_argon2_iterations.unwrap_or(10)
+            }
+            KsfAlgorithm::Scrypt(params) => {
+                let cost = (1u64 << params.log_n())
+                    .saturating_mul(params.r().into())
+                    .saturating_mul(params.p().into());
+                cost > limits.max_scrypt_cost.unwrap_or(1 << 23)
+            }
+            KsfAlgorithm::Pbkdf2Sha256(iterations) | KsfAlgorithm::Pbkdf2Sha512(iterations) => {
+                *iterations > limits.max_pbkdf2_iterations.unwrap_or(6_000_000)
+            }
+            _ => false,
+        };
+        let max_memory_kib = limits.max_memory_kib.unwrap_or(u32::pow(2, 21) - 1);
+        if exceeded || self.memory_kib() > max_memory_kib.into() {
+            return Err(Error::KsfCostLimit { context });
+        }
+        Ok(())
+    }
+
+    pub(crate) fn capture() -> Self {
+        CustomKsf {
+            algorithm: KsfAlgorithm::Capture(RefCell::new(None)),
+            salt: Vec::new(),
+            pepper: None,
+            out_of_memory: Cell::new(false),
+        }
+    }
+
+    pub(crate) fn captured_input(&self) -> Option<Vec<u8>> {
+        match &self.algorithm {
+            KsfAlgorithm::Capture(captured) => captured.borrow().clone(),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn into_job(self, input: Vec<u8>) -> KeyStretchingJob {
+        let state = match &self.algorithm {
+            #[cfg(feature = "argon2")]
+            KsfAlgorithm::Argon2(algorithm, version, params) => {
+                JobState::Argon2(Box::new(Argon2State::new(
+                    *algorithm,
+                    *version,
+                    params,
+                    &input,
+                    &self.salt,
+                    self.pepper.as_deref(),
+                    params.output_len().unwrap_or(input.len()),
+                )))
+            }
+            KsfAlgorithm::Pbkdf2Sha256(iterations) => {
+                JobState::Pbkdf2Sha256(Pbkdf2State::new(&input, *iterations))
+            }
+            KsfAlgorithm::Pbkdf2Sha512(iterations) => {
+                JobState::Pbkdf2Sha512(Pbkdf2State::new(&input, *iterations))
+            }
+            _ => JobState::Pending,
+        };
+        KeyStretchingJob {
+            ksf: self,
+            input,
+            state,
+        }
+    }
+}
+
+/// Key stretching split into steps of a given time budget, so that it can be
+/// driven cooperatively from the JavaScript event loop.
+pub(crate) struct KeyStretchingJob {
+    ksf: CustomKsf,
+    input: Vec<u8>,
+    state: JobState,
+}
+
+enum JobState {
+    /// Computed at once in the next step
+    Pending,
+    /// Its memory is allocated in the first step and released as soon as the
+    /// job is finished or dropped
+    #[cfg(feature = "argon2")]
+    Argon2(Box<Argon2State>),
+    Pbkdf2Sha256(Pbkdf2State<Hmac<Sha256>>),
+    Pbkdf2Sha512(Pbkdf2State<Hmac<Sha512>>),
+    Done(Vec<u8>),
+}
+
+impl KeyStretchingJob {
+    /// Runs the computation until `budget_millis` have passed and returns
+    /// whether it is finished.
+    pub(crate) fn step(&mut self, budget_millis: f64) -> Result<bool, InternalError> {
+        let deadline = now_millis() + budget_millis;
+        let mut output = vec![0; self.input.len()];
+        match &mut self.state {
+            JobState::Pending => self.ksf.stretch(&self.input, &mut output)?,
+            #[cfg(feature = "argon2")]
+            JobState::Argon2(state) => match state
+                .run(deadline)
+                .map_err(|_| self.ksf.allocation_failed())?
+            {
+                Some(tag) if tag.len() == output.len() => output = tag,
+                Some(tag) => expand_argon2_tag(&tag, &mut output)?,
+                None => return Ok(false),
+            },
+            JobState::Pbkdf2Sha256(state) => match state.run(&self.ksf.salt, deadline) {
+                Some(stretched) => output = stretched,
+                None => return Ok(false),
+            },
+            JobState::Pbkdf2Sha512(state) => match state.run(&self.ksf.salt, deadline) {
+                Some(stretched) => output = stretched,
+                None => return Ok(false),
+            },
+            JobState::Done(_) => return Ok(true),
+        }
+        self.state = JobState::Done(output);
+        Ok(true)
+    }
+
+    /// See [`CustomKsf::memory_error`].
+    pub(crate) fn memory_error(&self) -> Option<Error> {
+        self.ksf.memory_error()
+    }
+
+    /// Fraction of the work done, between 0 and 1.
+    pub(crate) fn progress(&self) -> f64 {
+        match &self.state {
+            #[cfg(feature = "argon2")]
+            JobState::Argon2(state) => state.progress(),
+            JobState::Pbkdf2Sha256(state) => state.progress(),
+            JobState::Pbkdf2Sha512(state) => state.progress(),
+            JobState::Done(_) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Returns a key stretching function yielding the computed output, once
+    /// the job is finished.
+    pub(crate) fn into_precomputed(self) -> Option<CustomKsf> {
+        match self.state {
+            JobState::Done(output) => Some(CustomKsf {
+                algorithm: KsfAlgorithm::Precomputed {
+                    input: self.input,
+                    output,
+                },
+                salt: self.ksf.salt,
+                pepper: None,
+                out_of_memory: Cell::new(false),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// PBKDF2 (RFC 8018) computed one HMAC invocation at a time.
+struct Pbkdf2State<M> {
+    prf: M,
+    iterations: u64,
+    /// Number of HMAC invocations done so far
+    done: u64,
+    u: Vec<u8>,
+    output: Vec<u8>,
+}
+
+impl<M: Mac + KeyInit + Clone> Pbkdf2State<M> {
+    /// Number of HMAC invocations between two checks of the deadline
+    const CHUNK: u64 = 1024;
+
+    fn new(password: &[u8], iterations: u32) -> Self {
+        Pbkdf2State {
+            prf: <M as KeyInit>::new_from_slice(password).expect("HMAC accepts keys of any length"),
+            iterations: iterations.into(),
+            done: 0,
+            u: Vec::new(),
+            output: vec![0; password.len()],
+        }
+    }
+
+    fn total(&self) -> u64 {
+        let blocks = self.output.len().div_ceil(M::output_size()) as u64;
+        blocks * self.iterations
+    }
+
+    fn progress(&self) -> f64 {
+        self.done as f64 / self.total() as f64
+    }
+
+    /// Returns the output once all iterations are done.
+    fn run(&mut self, salt: &[u8], deadline: f64) -> Option<Vec<u8>> {
+        let total = self.total();
+        while self.done < total {
+            let end = (self.done + Self::CHUNK).min(total);
+            while self.done < end {
+                let block = self.done / self.iterations;
+                let prf = self.prf.clone();
+                let u = if self.done.is_multiple_of(self.iterations) {
+                    prf.chain_update(salt)
+                        .chain_update((block as u32 + 1).to_be_bytes())
+                        .finalize()
+                } else {
+                    prf.chain_update(&self.u).finalize()
+                };
+                self.u = u.into_bytes().to_vec();
+                let offset = block as usize * self.u.len();
+                for (t, u) in self.output[offset..].iter_mut().zip(&self.u) {
+                    *t ^= u;
+                }
+                self.done += 1;
+            }
+            if now_millis() >= deadline {
+                break;
+            }
+        }
+        (self.done == total).then(|| self.output.clone())
+    }
+}
+
+#[cfg(feature = "argon2")]
+fn build_argon2_params(
     t_cost: u32,
     m_cost: u32,
     parallelism: u32,
-) -> Result<Option<CustomKsf>, Error> {
+    output_length: Option<u32>,
+) -> Result<Params, Error> {
     let mut param_builder = ParamsBuilder::default();
     param_builder.t_cost(t_cost);
     param_builder.m_cost(m_cost);
     param_builder.p_cost(parallelism);
+    if let Some(output_length) = output_length {
+        param_builder.output_len(output_length as usize);
+    }
+
+    param_builder.build().map_err(|error| {
+        let context = match error {
+            argon2::Error::TimeTooSmall => "Invalid keyStretching (argon2) iterations",
+            argon2::Error::MemoryTooLittle | argon2::Error::MemoryTooMuch => {
+                "Invalid keyStretching (argon2) memory"
+            }
+            argon2::Error::ThreadsTooFew | argon2::Error::ThreadsTooMany => {
+                "Invalid keyStretching (argon2) parallelism"
+            }
+            argon2::Error::OutputTooShort | argon2::Error::OutputTooLong => {
+                "Invalid keyStretching (argon2) outputLength"
+            }
+            _ => "Invalid keyStretching (argon2) combination",
+        };
+        Error::Internal {
+            context,
+            error: InternalError::KsfError,
+        }
+    })
+}
+
+#[cfg(feature = "argon2")]
+fn build_argon2_ksf(
+    t_cost: u32,
+    m_cost: u32,
+    parallelism: u32,
+    salt: Vec<u8>,
+) -> Result<Option<CustomKsf>, Error> {
+    let params = build_argon2_params(t_cost, m_cost, parallelism, None)?;
+    Ok(Some(CustomKsf {
+        algorithm: KsfAlgorithm::Argon2(Algorithm::Argon2id, Version::V0x13, params),
+        salt,
+        pepper: None,
+        out_of_memory: Cell::new(false),
+    }))
+}
+
+#[cfg(feature = "argon2")]
+fn build_argon2_custom_ksf(
+    algorithm: Option<Argon2AlgorithmConfig>,
+    version: Option<Argon2VersionConfig>,
+    t_cost: u32,
+    m_cost: u32,
+    parallelism: u32,
+    output_length: Option<u32>,
+    salt: Vec<u8>,
+) -> Result<Option<CustomKsf>, Error> {
+    let params = build_argon2_params(t_cost, m_cost, parallelism, output_length)?;
+    Ok(Some(CustomKsf {
+        algorithm: KsfAlgorithm::Argon2(
+            algorithm.unwrap_or_default().into(),
+            version.unwrap_or_default().into(),
+            params,
+        ),
+        salt,
+        pepper: None,
+        out_of_memory: Cell::new(false),
+    }))
+}
+
+#[cfg(not(feature = "argon2"))]
+fn build_argon2_ksf(
+    _t_cost: u32,
+    _m_cost: u32,
+    _parallelism: u32,
+    _salt: Vec<u8>,
+) -> Result<Option<CustomKsf>, Error> {
+    Err(Error::Internal {
+        context: "keyStretching (argon2id) is not available in this build",
+        error: InternalError::KsfError,
+    })
+}
+
+#[cfg(not(feature = "argon2"))]
+fn build_argon2_custom_ksf(
+    _algorithm: Option<Argon2AlgorithmConfig>,
+    _version: Option<Argon2VersionConfig>,
+    _t_cost: u32,
+    _m_cost: u32,
+    _parallelism: u32,
+    _output_length: Option<u32>,
+    _salt: Vec<u8>,
+) -> Result<Option<CustomKsf>, Error> {
+    Err(Error::Internal {
+        context: "keyStretching (argon2) is not available in this build",
+        error: InternalError::KsfError,
+    })
+}
+
+/// Finds Argon2id parameters taking about `target_millis` on the current
+/// runtime. The memory starts at `max_memory` (KiB) and is halved while a
+/// single iteration exceeds the target; the remaining budget is spent on
+/// iterations.
+#[cfg(feature = "argon2")]
+pub(crate) fn calibrate_argon2(
+    target_millis: f64,
+    max_memory: u32,
+) -> JsResult<KeyStretchingFunctionConfig> {
+    const PARALLELISM: u32 = 4;
+    const MIN_MEMORY: u32 = 8 * 1024;
+
+    let mut memory = memory_limit_kib(Some(max_memory)) as u32;
+    loop {
+        let ksf = build_argon2_ksf(1, memory, PARALLELISM, vec![0; DEFAULT_SALT_LEN])?
+            .unwrap_or_default();
+        let start = now_millis();
+        ksf.hash(GenericArray::<u8, U64>::default()).map_err(|_| {
+            ksf.memory_error().unwrap_or(Error::KsfFailed {
+                context: "calibrate keyStretching",
+            })
+        })?;
+        let elapsed = (now_millis() - start).max(1.0);
+
+        if elapsed > target_millis && memory / 2 >= MIN_MEMORY {
+            memory /= 2;
+            continue;
+        }
+
+        return Ok(KeyStretchingFunctionConfig::Custom {
+            iterations: ((target_millis / elapsed) as u32).max(1),
+            memory,
+            parallelism: PARALLELISM,
+        });
+    }
+}
 
-    if let Ok(params) = param_builder.build() {
-        let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-        return Ok(Some(CustomKsf { argon }));
+fn build_scrypt_ksf(n: u64, r: u32, p: u32, salt: Vec<u8>) -> Result<Option<CustomKsf>, Error> {
+    if n.is_power_of_two() && n > 1 {
+        let log_n = n.trailing_zeros() as u8;
+        if let Ok(params) = scrypt::Params::new(log_n, r, p, scrypt::Params::RECOMMENDED_LEN) {
+            return Ok(Some(CustomKsf {
+                algorithm: KsfAlgorithm::Scrypt(params),
+                salt,
+                pepper: None,
+                out_of_memory: Cell::new(false),
+            }));
+        }
     }
 
     Err(Error::Internal {
-        context: "Invalid keyStretching (argon2id) combination",
+        context: "Invalid keyStretching (scrypt) combination",
         error: InternalError::KsfError,
     })
 }
 
+fn build_pbkdf2_ksf(algorithm: KsfAlgorithm, salt: Vec<u8>) -> Result<Option<CustomKsf>, Error> {
+    match algorithm {
+        KsfAlgorithm::Pbkdf2Sha256(0) | KsfAlgorithm::Pbkdf2Sha512(0) => Err(Error::Internal {
+            context: "Invalid keyStretching (pbkdf2) iterations",
+            error: InternalError::KsfError,
+        }),
+        algorithm => Ok(Some(CustomKsf {
+            algorithm,
+            salt,
+            pepper: None,
+            out_of_memory: Cell::new(false),
+        })),
+    }
+}
+
+pub(crate) fn get_ksf_salt(
+    salt_config: Option<KsfSaltConfig>,
+    identifiers: &Option<CustomIdentifiers>,
+) -> JsResult<Vec<u8>> {
+    match salt_config.unwrap_or_default() {
+        KsfSaltConfig::Zero => Ok(vec![0; DEFAULT_SALT_LEN]),
+        KsfSaltConfig::Identifiers => {
+            let (client, server) = match identifiers {
+                Some(CustomIdentifiers { client, server })
+                    if client.is_some() || server.is_some() =>
+                {
+                    (client.as_deref(), server.as_deref())
+                }
+                _ => {
+                    return Err(Error::Internal {
+                        context: "ksfSalt \"identifiers\" requires client or server identifiers",
+                        error: InternalError::KsfError,
+                    })
+                }
+            };
+            let mut hasher = Sha512::new_with_prefix(STR_KSF_SALT);
+            for identifier in [client, server] {
+                let identifier = identifier.unwrap_or_default().as_bytes();
+                hasher.update((identifier.len() as u64).to_be_bytes());
+                hasher.update(identifier);
+            }
+            Ok(hasher.finalize()[..DEFAULT_SALT_LEN].to_vec())
+        }
+        KsfSaltConfig::Custom(salt) => {
+            let salt = base64_decode("ksfSalt", salt)?;
+            if salt.len() < MIN_SALT_LEN {
+                return Err(Error::Internal {
+                    context: "Invalid ksfSalt (at least 8 bytes are required)",
+                    error: InternalError::KsfError,
+                });
+            }
+            Ok(salt)
+        }
+    }
+}
+
+/// Mixes an application-wide secret into Argon2, the other key stretching
+/// functions have no secret input.
+fn apply_pepper(
+    ksf: Option<CustomKsf>,
+    pepper: Option<Vec<u8>>,
+) -> Result<Option<CustomKsf>, Error> {
+    match (ksf, pepper) {
+        (ksf, None) => Ok(ksf),
+        #[cfg(feature = "argon2")]
+        (
+            Some(
+                ksf @ CustomKsf {
+                    algorithm: KsfAlgorithm::Argon2(..),
+                    ..
+                },
+            ),
+            pepper,
+        ) => Ok(Some(CustomKsf { pepper, ..ksf })),
+        _ => Err(Error::Internal {
+            context: "pepper requires argon2 keyStretching",
+            error: InternalError::KsfError,
+        }),
+    }
+}
+
 pub(crate) fn get_custom_ksf(
     ksf_config: Option<KeyStretchingFunctionConfig>,
+    salt: Vec<u8>,
+    pepper: Option<Vec<u8>>,
 ) -> Result<Option<CustomKsf>, Error> {
-    let config = ksf_config.unwrap_or(KeyStretchingFunctionConfig::MemoryConstrained);
+    let config = ksf_config.unwrap_or_default();
 
-    match config {
+    let ksf = match config {
         // https://www.rfc-editor.org/rfc/rfc9106.html#section-4-6.1
         // using the recommended parameters for Argon2id except we use 2^21-1 since 2^21 crashes in browsers
         KeyStretchingFunctionConfig::Rfc9106Recommended => {
-            build_argon2_ksf(1, u32::pow(2, 21) - 1, 4)
+            build_argon2_ksf(1, u32::pow(2, 21) - 1, 4, salt)
         }
         // https://www.rfc-editor.org/rfc/rfc9106.html#section-4-6.2
-        KeyStretchingFunctionConfig::MemoryConstrained => build_argon2_ksf(3, u32::pow(2, 16), 4),
+        KeyStretchingFunctionConfig::MemoryConstrained => {
+            build_argon2_ksf(3, u32::pow(2, 16), 4, salt)
+        }
         // https://libsodium.gitbook.io/doc/password_hashing/default_phf#key-derivation
-        KeyStretchingFunctionConfig::LibsodiumModerate => build_argon2_ksf(3, u32::pow(2, 18), 4),
+        KeyStretchingFunctionConfig::LibsodiumModerate => {
+            build_argon2_ksf(3, u32::pow(2, 18), 4, salt)
+        }
         KeyStretchingFunctionConfig::Custom {
             iterations,
             memory,
             parallelism,
-        } => build_argon2_ksf(iterations, memory, parallelism),
-    }
+        } => build_argon2_ksf(iterations, memory, parallelism, salt),
+        KeyStretchingFunctionConfig::Argon2Custom {
+            algorithm,
+            version,
+            iterations,
+            memory,
+            parallelism,
+            output_length,
+        } => build_argon2_custom_ksf(
+            algorithm,
+            version,
+            iterations,
+            memory,
+            parallelism,
+            output_length,
+            salt,
+        ),
+        // https://pkg.go.dev/golang.org/x/crypto/scrypt#Key
+        KeyStretchingFunctionConfig::ScryptInteractive => {
+            build_scrypt_ksf(u64::pow(2, 15), 8, 1, salt)
+        }
+        // https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#scrypt
+        KeyStretchingFunctionConfig::ScryptRecommended => {
+            build_scrypt_ksf(u64::pow(2, 17), 8, 1, salt)
+        }
+        KeyStretchingFunctionConfig::ScryptCustom { n, r, p } => build_scrypt_ksf(n, r, p, salt),
+        // https://cheatsheetseries.owasp.org/cheatsheets/Password_Storage_Cheat_Sheet.html#pbkdf2
+        KeyStretchingFunctionConfig::Pbkdf2Sha256Recommended => {
+            build_pbkdf2_ksf(KsfAlgorithm::Pbkdf2Sha256(600_000), salt)
+        }
+        KeyStretchingFunctionConfig::Pbkdf2Sha512Recommended => {
+            build_pbkdf2_ksf(KsfAlgorithm::Pbkdf2Sha512(210_000), salt)
+        }
+        KeyStretchingFunctionConfig::Pbkdf2Sha256Custom { iterations } => {
+            build_pbkdf2_ksf(KsfAlgorithm::Pbkdf2Sha256(iterations), salt)
+        }
+        KeyStretchingFunctionConfig::Pbkdf2Sha512Custom { iterations } => {
+            build_pbkdf2_ksf(KsfAlgorithm::Pbkdf2Sha512(iterations), salt)
+        }
+        KeyStretchingFunctionConfig::Identity => Ok(Some(CustomKsf {
+            algorithm: KsfAlgorithm::Identity,
+            salt,
+            pepper: None,
+            out_of_memory: Cell::new(false),
+        })),
+        KeyStretchingFunctionConfig::Callback { function } => Ok(Some(CustomKsf {
+            algorithm: KsfAlgorithm::Callback(function),
+            salt,
+            pepper: None,
+            out_of_memory: Cell::new(false),
+        })),
+    }?;
+    apply_pepper(ksf, pepper)
 }
@@ -1,64 +1,1564 @@
 pub mod client;
 pub mod server;
+pub mod session;
+pub mod task;
 
+#[cfg(feature = "argon2")]
+mod argon2_state;
 mod base64;
+mod channel;
 mod cipher_suite;
 mod error;
+mod export_key;
 mod identifiers;
+mod kem;
 mod ksf;
+mod metadata;
+#[cfg(feature = "parallel")]
+mod parallel;
+mod upgrade;
 mod utils;
 
 // -----------------------------------------------------------------------------
 
 #[cfg(test)]
 mod tests {
+    use opaque_ke::ServerSetup;
+
+    use crate::base64::{base64_decode, base64_encode, JsResult};
+    use crate::channel::{ChannelRole, SecureChannel};
+    use crate::cipher_suite::{
+        CipherSuiteConfig, DefaultCipherSuite, P256CipherSuite, P521CipherSuite,
+    };
     use crate::client::*;
+    use crate::error::ErrorCode;
+    use crate::identifiers::CustomIdentifiers;
+    #[cfg(feature = "argon2")]
+    use crate::ksf::{Argon2AlgorithmConfig, Argon2VersionConfig};
+    use crate::ksf::{KeyStretchingFunctionConfig, KeyStretchingLimits, KsfSaltConfig};
+    #[cfg(feature = "argon2")]
+    use crate::metadata::CredentialMetadata;
     use crate::server::*;
+    use crate::session::{
+        ClientLoginSession, ClientRegistrationSession, FinishClientLoginSessionParams,
+        FinishClientRegistrationSessionParams,
+    };
+    use crate::task::KeyStretchingTask;
 
     #[test]
+    #[cfg(feature = "argon2")]
     fn key_exchange() {
-        // Server configuration
-        let server_setup = create_server_setup();
+        run_key_exchange(None, None);
+    }
 
-        // Client configuration
-        let user_identifier = "john.doe@example.com";
-        let password = "_P4ssw0rd123!";
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_exchange_p256() {
+        run_key_exchange(Some(CipherSuiteConfig::P256), None);
+    }
 
-        // Registration
-        let registration_record = {
-            // Client starts registration
-            let client_reg_result = start_client_registration(StartClientRegistrationParams {
-                password: password.to_string(),
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_exchange_p384() {
+        run_key_exchange(Some(CipherSuiteConfig::P384), None);
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_exchange_p521() {
+        run_key_exchange(Some(CipherSuiteConfig::P521), None);
+    }
+
+    #[test]
+    fn mixed_cipher_suites_are_rejected() {
+        let suites = [
+            None,
+            Some(CipherSuiteConfig::P256),
+            Some(CipherSuiteConfig::P384),
+            Some(CipherSuiteConfig::P521),
+        ];
+        for client_suite in suites {
+            let registration_request = start_client_registration(StartClientRegistrationParams {
+                password: PASSWORD.to_string(),
+                cipher_suite: client_suite,
+            })
+            .unwrap()
+            .registration_request;
+
+            for server_suite in suites {
+                let result = registration_response(CreateServerRegistrationResponseParams {
+                    server_setup: create_server_setup(server_suite),
+                    user_identifier: USER_IDENTIFIER.to_string(),
+                    registration_request: registration_request.clone(),
+                    cipher_suite: server_suite,
+                });
+                if client_suite == server_suite {
+                    assert!(result.is_ok());
+                } else {
+                    assert_eq!(result.err().unwrap().code(), ErrorCode::CipherSuiteMismatch);
+                }
+            }
+
+            // Lengths of no suite's message are malformed, not mismatched; a
+            // single byte less would be the length of the next smaller suite
+            let mut truncated = base64_decode("registrationRequest", &registration_request)
+                .ok()
+                .unwrap();
+            truncated.truncate(truncated.len() - 2);
+            let result = registration_response(CreateServerRegistrationResponseParams {
+                server_setup: create_server_setup(client_suite),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: base64_encode(truncated),
+                cipher_suite: client_suite,
+            });
+            assert_eq!(
+                result.err().unwrap().code(),
+                ErrorCode::DeserializationFailed
+            );
+        }
+
+        let server_setup = base64_decode(
+            "serverSetup",
+            create_server_setup(Some(CipherSuiteConfig::P384)),
+        )
+        .ok()
+        .unwrap();
+        assert!(ServerSetup::<DefaultCipherSuite>::deserialize(&server_setup).is_err());
+        assert!(ServerSetup::<P256CipherSuite>::deserialize(&server_setup).is_err());
+        assert!(ServerSetup::<P521CipherSuite>::deserialize(&server_setup).is_err());
+    }
+
+    #[test]
+    fn malformed_login_response_is_a_failure() {
+        for cipher_suite in [None, Some(CipherSuiteConfig::Ristretto255MlKem768)] {
+            let server_setup = create_server_setup(cipher_suite);
+            let key_stretching = Some(KeyStretchingFunctionConfig::Identity);
+            let registration_record = register(&server_setup, cipher_suite, key_stretching.clone());
+
+            let client_login_result = start_client_login(StartClientLoginParams {
+                password: PASSWORD.to_string(),
+                cipher_suite,
             })
             .unwrap();
+            let login_response = start_server_login(StartServerLoginParams {
+                server_setup: server_setup.clone(),
+                registration_record: Some(registration_record),
+                start_login_request: client_login_result.start_login_request,
+                user_identifier: USER_IDENTIFIER.to_string(),
+                identifiers: None,
+                cipher_suite,
+                credential_metadata: None,
+                context: None,
+            })
+            .unwrap()
+            .login_response;
+            let login_response_bytes = base64_decode("loginResponse", &login_response)
+                .ok()
+                .unwrap();
 
-            // Server handles registration request
-            let server_reg_result =
-                create_server_registration_response(CreateServerRegistrationResponseParams {
-                    server_setup: server_setup.clone(),
-                    user_identifier: user_identifier.to_string(),
-                    registration_request: client_reg_result.registration_request,
-                })
+            for login_response in [
+                "not base64!".to_string(),
+                String::new(),
+                base64_encode(&login_response_bytes[1..]),
+                base64_encode([&login_response_bytes[..], &[0]].concat()),
+            ] {
+                let params = FinishClientLoginParams {
+                    client_login_state: client_login_result.client_login_state.clone(),
+                    key_stretching_function_config: key_stretching.clone(),
+                    cipher_suite,
+                    shared: FinishClientLoginSharedParams {
+                        login_response,
+                        password: PASSWORD.to_string(),
+                        ..Default::default()
+                    },
+                };
+                let custom_ksf = get_login_ksf(
+                    &params.shared,
+                    params.key_stretching_function_config.clone(),
+                    params.cipher_suite,
+                )
+                .ok()
                 .unwrap();
+                let result = finish_login(&params, custom_ksf.as_ref()).ok().unwrap();
+                assert_eq!(
+                    failure_reason(result),
+                    Some(LoginFailureReason::MalformedResponse)
+                );
+            }
+        }
+    }
 
-            // Client finishes registration
-            let client_finish_result = finish_client_registration(FinishClientRegistrationParams {
-                password: password.to_string(),
+    #[test]
+    fn key_exchange_scrypt() {
+        run_key_exchange(
+            None,
+            Some(KeyStretchingFunctionConfig::ScryptCustom {
+                n: 1024,
+                r: 8,
+                p: 1,
+            }),
+        );
+    }
+
+    #[test]
+    fn key_exchange_pbkdf2() {
+        run_key_exchange(
+            None,
+            Some(KeyStretchingFunctionConfig::Pbkdf2Sha256Custom { iterations: 1000 }),
+        );
+        run_key_exchange(
+            None,
+            Some(KeyStretchingFunctionConfig::Pbkdf2Sha512Custom { iterations: 1000 }),
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_exchange_argon2_custom() {
+        run_key_exchange(
+            None,
+            Some(KeyStretchingFunctionConfig::Argon2Custom {
+                algorithm: Some(Argon2AlgorithmConfig::Argon2i),
+                version: Some(Argon2VersionConfig::V0x10),
+                iterations: 1,
+                memory: 8,
+                parallelism: 1,
+                output_length: Some(32),
+            }),
+        );
+    }
+
+    /// Test vectors of RFC 9106 section 5 (version 0x13) and of the reference
+    /// implementation (version 0x10), computed at once and in steps.
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn argon2_state_known_answers() {
+        use argon2::{Algorithm, AssociatedData, ParamsBuilder, Version};
+
+        use crate::argon2_state::Argon2State;
+
+        let params = ParamsBuilder::new()
+            .m_cost(32)
+            .t_cost(3)
+            .p_cost(4)
+            .data(AssociatedData::new(&[4; 12]).unwrap())
+            .build()
+            .unwrap();
+        let vectors = [
+            (
+                Algorithm::Argon2d,
+                Version::V0x10,
+                "96a9d4e5a1734092c85e29f410a45914a5dd1f5cbf08b2670da68a0285abf32b",
+            ),
+            (
+                Algorithm::Argon2i,
+                Version::V0x10,
+                "87aeedd6517ab830cd9765cd8231abb2e647a5dee08f7c05e02fcb763335d0fd",
+            ),
+            (
+                Algorithm::Argon2id,
+                Version::V0x10,
+                "b64615f07789b66b645b67ee9ed3b377ae350b6bfcbb0fc95141ea8f322613c0",
+            ),
+            (
+                Algorithm::Argon2d,
+                Version::V0x13,
+                "512b391b6f1162975371d30919734294f868e3be3984f3c1a13a4db9fabe4acb",
+            ),
+            (
+                Algorithm::Argon2i,
+                Version::V0x13,
+                "c814d9d1dc7f37aa13f0d77f2494bda1c8de6b016dd388d29952a4c4672b6ce8",
+            ),
+            (
+                Algorithm::Argon2id,
+                Version::V0x13,
+                "0d640df58d78766c08c037a34a8b53c9d01ef0452d75b65eb52520e96b01e659",
+            ),
+        ];
+        for (algorithm, version, expected_tag) in vectors {
+            let expected_tag = (0..expected_tag.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&expected_tag[i..i + 2], 16).unwrap())
+                .collect::<Vec<_>>();
+            let state = || {
+                Argon2State::new(
+                    algorithm,
+                    version,
+                    &params,
+                    &[1; 32],
+                    &[2; 16],
+                    Some(&[3; 8]),
+                    32,
+                )
+            };
+            assert_eq!(
+                state().run(f64::INFINITY).unwrap().unwrap(),
+                expected_tag,
+                "{:?} {:?}",
+                algorithm,
+                version
+            );
+
+            let mut stepped = state();
+            let tag = loop {
+                if let Some(tag) = stepped.run(0.0).unwrap() {
+                    break tag;
+                }
+            };
+            assert_eq!(tag, expected_tag, "{:?} {:?} in steps", algorithm, version);
+        }
+    }
+
+    #[test]
+    fn key_exchange_without_key_stretching() {
+        run_key_exchange(None, Some(KeyStretchingFunctionConfig::Identity));
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_exchange_ml_kem() {
+        run_key_exchange(Some(CipherSuiteConfig::Ristretto255MlKem768), None);
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn ml_kem_ciphertext_is_authenticated() {
+        let cipher_suite = Some(CipherSuiteConfig::Ristretto255MlKem768);
+        let server_setup = create_server_setup(cipher_suite);
+        let registration_record = register(&server_setup, cipher_suite, None);
+
+        let client_login_result = start_client_login(StartClientLoginParams {
+            password: PASSWORD.to_string(),
+            cipher_suite,
+        })
+        .unwrap();
+        let server_login_result = start_server_login(StartServerLoginParams {
+            server_setup,
+            registration_record: Some(registration_record),
+            start_login_request: client_login_result.start_login_request,
+            user_identifier: USER_IDENTIFIER.to_string(),
+            identifiers: None,
+            cipher_suite,
+            credential_metadata: None,
+            context: None,
+        })
+        .unwrap();
+
+        // Flip a bit in the ML-KEM ciphertext at the end of the login response
+        let mut login_response = base64_decode("loginResponse", server_login_result.login_response)
+            .ok()
+            .unwrap();
+        *login_response.last_mut().unwrap() ^= 1;
+
+        let client_finish_result = finish_client_login(FinishClientLoginParams {
+            client_login_state: client_login_result.client_login_state,
+            key_stretching_function_config: None,
+            cipher_suite,
+            shared: FinishClientLoginSharedParams {
+                login_response: base64_encode(login_response),
+                password: PASSWORD.to_string(),
+                ..Default::default()
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            failure_reason(client_finish_result),
+            Some(LoginFailureReason::InvalidCredentials)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn ksf_salt_from_identifiers() {
+        let server_setup = create_server_setup(None);
+        let key_stretching = || {
+            Some(KeyStretchingFunctionConfig::Custom {
+                iterations: 1,
+                memory: 8,
+                parallelism: 1,
+            })
+        };
+        let options = Options {
+            identifiers: Some(CustomIdentifiers {
+                client: Some(USER_IDENTIFIER.to_string()),
+                server: None,
+            }),
+            ksf_salt: Some(KsfSaltConfig::Identifiers),
+            ..Default::default()
+        };
+        let registration_record =
+            register_with(&server_setup, PASSWORD, key_stretching(), &options)
+                .ok()
+                .unwrap();
+
+        let login = |options: &Options| {
+            login_with(
+                &server_setup,
+                &registration_record,
+                PASSWORD,
+                key_stretching(),
+                options,
+            )
+            .ok()
+            .unwrap()
+        };
+        assert!(login(&options).is_ok());
+        let zero_salt = Options {
+            ksf_salt: None,
+            ..options.clone()
+        };
+        assert!(login(&zero_salt).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn calibrate_key_stretching_respects_memory_bound() {
+        let config = calibrate_key_stretching(CalibrateKeyStretchingParams {
+            target_millis: 20.0,
+            max_memory_kib: Some(1024),
+        })
+        .ok()
+        .unwrap();
+        match config {
+            KeyStretchingFunctionConfig::Custom {
+                iterations, memory, ..
+            } => {
+                assert!(iterations >= 1);
+                assert!(memory <= 1024);
+            }
+            _ => panic!("calibration should return custom Argon2id parameters"),
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_stretching_memory_limit() {
+        let estimate = |key_stretching, max_memory_kib| {
+            estimate_cost(EstimateKeyStretchingCostParams {
+                key_stretching_function_config: Some(key_stretching),
+                max_memory_kib,
+            })
+            .ok()
+            .unwrap()
+        };
+        let argon2 = KeyStretchingFunctionConfig::Custom {
+            iterations: 1,
+            memory: 1024,
+            parallelism: 1,
+        };
+
+        let cost = estimate(argon2.clone(), None);
+        assert_eq!(cost.memory_kib, 1024);
+        assert!(cost.within_limit);
+        let cost = estimate(
+            KeyStretchingFunctionConfig::ScryptCustom {
+                n: 1024,
+                r: 8,
+                p: 1,
+            },
+            Some(1024),
+        );
+        assert_eq!(cost.memory_kib, 1027);
+        assert!(!cost.within_limit);
+        assert_eq!(
+            estimate(KeyStretchingFunctionConfig::Identity, Some(0)).memory_kib,
+            0
+        );
+
+        let params = |max_memory_kib| FinishClientRegistrationSharedParams {
+            password: PASSWORD.to_string(),
+            registration_response: String::new(),
+            max_memory_kib,
+            ..Default::default()
+        };
+        assert!(get_registration_ksf(&params(Some(1024)), Some(argon2.clone())).is_ok());
+        assert!(get_registration_ksf(&params(Some(1023)), Some(argon2.clone())).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn error_codes() {
+        let code = |key_stretching, pepper: &str, registration_response: &str| {
+            let params = FinishClientRegistrationParams {
+                client_registration_state: String::new(),
+                key_stretching_function_config: Some(key_stretching),
+                cipher_suite: None,
+                shared: FinishClientRegistrationSharedParams {
+                    password: PASSWORD.to_string(),
+                    registration_response: registration_response.to_string(),
+                    pepper: Some(pepper.to_string()),
+                    max_memory_kib: Some(1024),
+                    ..Default::default()
+                },
+            };
+            match get_registration_ksf(
+                &params.shared,
+                params.key_stretching_function_config.clone(),
+            ) {
+                Ok(custom_ksf) => finish_registration(&params, custom_ksf.as_ref()).err(),
+                Err(error) => Some(error),
+            }
+            .map(|error| error.code())
+        };
+        let argon2 = |memory| KeyStretchingFunctionConfig::Custom {
+            iterations: 1,
+            memory,
+            parallelism: 1,
+        };
+
+        assert_eq!(
+            code(argon2(8), "not base64!", ""),
+            Some(ErrorCode::InvalidBase64)
+        );
+        assert_eq!(
+            code(
+                KeyStretchingFunctionConfig::ScryptCustom { n: 3, r: 8, p: 1 },
+                "",
+                ""
+            ),
+            Some(ErrorCode::KsfInvalidParams)
+        );
+        assert_eq!(code(argon2(2048), "", ""), Some(ErrorCode::KsfMemoryLimit));
+        assert_eq!(
+            code(argon2(8), "", ""),
+            Some(ErrorCode::DeserializationFailed)
+        );
+
+        // A valid registration response of another cipher suite
+        let p256 = Some(CipherSuiteConfig::P256);
+        let registration_request = start_client_registration(StartClientRegistrationParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: p256,
+        })
+        .unwrap()
+        .registration_request;
+        let p256_response = registration_response(CreateServerRegistrationResponseParams {
+            server_setup: create_server_setup(p256),
+            user_identifier: USER_IDENTIFIER.to_string(),
+            registration_request,
+            cipher_suite: p256,
+        })
+        .ok()
+        .unwrap()
+        .registration_response;
+        assert_eq!(
+            code(argon2(8), "", &p256_response),
+            Some(ErrorCode::CipherSuiteMismatch)
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn ksf_pepper() {
+        let server_setup = create_server_setup(None);
+        let key_stretching = || {
+            Some(KeyStretchingFunctionConfig::Custom {
+                iterations: 1,
+                memory: 8,
+                parallelism: 1,
+            })
+        };
+        let options = Options {
+            pepper: Some(base64_encode(b"application-wide pepper")),
+            ..Default::default()
+        };
+        let registration_record =
+            register_with(&server_setup, PASSWORD, key_stretching(), &options)
+                .ok()
+                .unwrap();
+
+        let login = |options: &Options| {
+            login_with(
+                &server_setup,
+                &registration_record,
+                PASSWORD,
+                key_stretching(),
+                options,
+            )
+            .ok()
+            .unwrap()
+        };
+        assert!(login(&options).is_ok());
+        assert!(login(&Options::default()).is_err());
+    }
+
+    #[test]
+    fn credential_metadata_is_applied_on_login() {
+        let server_setup = create_server_setup(None);
+
+        let client_reg_result = start_client_registration(StartClientRegistrationParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: None,
+        })
+        .unwrap();
+        let server_reg_result =
+            create_server_registration_response(CreateServerRegistrationResponseParams {
+                server_setup: server_setup.clone(),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: client_reg_result.registration_request,
+                cipher_suite: None,
+            })
+            .unwrap();
+        let client_finish_result = finish_client_registration(FinishClientRegistrationParams {
+            client_registration_state: client_reg_result.client_registration_state,
+            key_stretching_function_config: Some(KeyStretchingFunctionConfig::ScryptCustom {
+                n: 1024,
+                r: 8,
+                p: 1,
+            }),
+            cipher_suite: None,
+            shared: FinishClientRegistrationSharedParams {
+                password: PASSWORD.to_string(),
                 registration_response: server_reg_result.registration_response,
-                client_registration_state: client_reg_result.client_registration_state,
+                ksf_salt: Some(KsfSaltConfig::Custom(base64_encode([7; 16]))),
+                ..Default::default()
+            },
+        })
+        .unwrap();
+
+        let login = |credential_metadata, key_stretching_limits| -> JsResult<_> {
+            let client_login_result = start_client_login(StartClientLoginParams {
+                password: PASSWORD.to_string(),
+                cipher_suite: None,
+            })
+            .unwrap();
+            let server_login_result = start_server_login(StartServerLoginParams {
+                server_setup: server_setup.clone(),
+                registration_record: Some(client_finish_result.registration_record.clone()),
+                start_login_request: client_login_result.start_login_request,
+                user_identifier: USER_IDENTIFIER.to_string(),
                 identifiers: None,
+                cipher_suite: None,
+                credential_metadata: Some(credential_metadata),
+                context: None,
+            })
+            .unwrap();
+            let params = FinishClientLoginParams {
+                client_login_state: client_login_result.client_login_state,
                 key_stretching_function_config: None,
+                cipher_suite: None,
+                shared: FinishClientLoginSharedParams {
+                    login_response: server_login_result.login_response,
+                    password: PASSWORD.to_string(),
+                    credential_metadata: server_login_result.credential_metadata,
+                    key_stretching_limits,
+                    ..Default::default()
+                },
+            };
+            let custom_ksf = get_login_ksf(
+                &params.shared,
+                params.key_stretching_function_config.clone(),
+                params.cipher_suite,
+            )?;
+            finish_login(&params, custom_ksf.as_ref())
+        };
+
+        let metadata = || client_finish_result.credential_metadata.clone();
+        assert!(success(login(metadata(), None).ok().unwrap()).is_some());
+
+        // The metadata is unauthenticated until the login succeeds, so its
+        // key stretching function is bounded before stretching
+        let limits = KeyStretchingLimits {
+            max_scrypt_cost: Some(1024 * 8 - 1),
+            ..Default::default()
+        };
+        let error = login(metadata(), Some(limits)).err().unwrap();
+        assert_eq!(error.code(), ErrorCode::KsfCostLimit);
+
+        // as is its memory, even below what the wasm instance can address
+        #[cfg(feature = "argon2")]
+        {
+            let hostile_metadata = CredentialMetadata::new(
+                None,
+                &Some(KeyStretchingFunctionConfig::Custom {
+                    iterations: 1,
+                    memory: u32::pow(2, 22),
+                    parallelism: 1,
+                }),
+                &Some(KsfSaltConfig::Custom(base64_encode([7; 16]))),
+            )
+            .serialize(&[0; 64])
+            .ok()
+            .unwrap();
+            let error = login(base64_encode(hostile_metadata), None).err().unwrap();
+            assert_eq!(error.code(), ErrorCode::KsfCostLimit);
+        }
+
+        // Flip a bit in the MAC at the end of the metadata
+        let mut credential_metadata = base64_decode(
+            "credentialMetadata",
+            &client_finish_result.credential_metadata,
+        )
+        .ok()
+        .unwrap();
+        *credential_metadata.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            failure_reason(
+                login(base64_encode(credential_metadata), None)
+                    .ok()
+                    .unwrap()
+            ),
+            Some(LoginFailureReason::InvalidCredentialMetadata)
+        );
+    }
+
+    #[test]
+    fn registration_upgrade() {
+        let server_setup = create_server_setup(None);
+        let old_key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+        let new_key_stretching =
+            || Some(KeyStretchingFunctionConfig::Pbkdf2Sha256Custom { iterations: 1000 });
+        let registration_record = register(&server_setup, None, old_key_stretching());
+
+        let (client_session_key, server_session_key) =
+            login(&server_setup, &registration_record, old_key_stretching()).unwrap();
+
+        let new_registration_record = register(&server_setup, None, new_key_stretching());
+        let upgrade_tag =
+            authenticate_registration_upgrade(AuthenticateRegistrationUpgradeParams {
+                session_key: client_session_key,
+                registration_record: new_registration_record.clone(),
+                credential_metadata: None,
+            })
+            .ok()
+            .unwrap()
+            .upgrade_tag;
+
+        let verify = |session_key| {
+            verify_registration_upgrade(VerifyRegistrationUpgradeParams {
+                session_key,
+                registration_record: new_registration_record.clone(),
+                credential_metadata: None,
+                upgrade_tag: upgrade_tag.clone(),
+                cipher_suite: None,
             })
+            .ok()
+            .unwrap()
+        };
+        assert!(verify(server_session_key));
+        assert!(!verify(base64_encode([0; 64])));
+
+        assert!(login(
+            &server_setup,
+            &new_registration_record,
+            new_key_stretching()
+        )
+        .is_some());
+        assert!(login(
+            &server_setup,
+            &new_registration_record,
+            old_key_stretching()
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn password_change() {
+        const NEW_PASSWORD: &str = "new-password";
+        let server_setup = create_server_setup(None);
+        let key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+        let registration_record = register(&server_setup, None, key_stretching());
+
+        let (client_session_key, server_session_key) =
+            login(&server_setup, &registration_record, key_stretching()).unwrap();
+
+        let new_registration_record =
+            register_password(&server_setup, NEW_PASSWORD, None, key_stretching());
+        let password_change_tag = authenticate_password_change(AuthenticatePasswordChangeParams {
+            session_key: client_session_key.clone(),
+            registration_record: new_registration_record.clone(),
+            credential_metadata: None,
+        })
+        .ok()
+        .unwrap()
+        .password_change_tag;
+
+        let verify = |session_key, password_change_tag| {
+            verify_password_change(VerifyPasswordChangeParams {
+                session_key,
+                registration_record: new_registration_record.clone(),
+                credential_metadata: None,
+                password_change_tag,
+                cipher_suite: None,
+            })
+            .ok()
+            .unwrap()
+        };
+        assert!(verify(
+            server_session_key.clone(),
+            password_change_tag.clone()
+        ));
+        assert!(!verify(base64_encode([0; 64]), password_change_tag.clone()));
+
+        // Registration upgrade tags are not accepted for password changes
+        let upgrade_tag =
+            authenticate_registration_upgrade(AuthenticateRegistrationUpgradeParams {
+                session_key: client_session_key,
+                registration_record: new_registration_record.clone(),
+                credential_metadata: None,
+            })
+            .ok()
+            .unwrap()
+            .upgrade_tag;
+        assert!(!verify(server_session_key.clone(), upgrade_tag));
+        // and password change tags are not accepted for registration upgrades
+        assert!(
+            !verify_registration_upgrade(VerifyRegistrationUpgradeParams {
+                session_key: server_session_key,
+                registration_record: new_registration_record.clone(),
+                credential_metadata: None,
+                upgrade_tag: password_change_tag,
+                cipher_suite: None,
+            })
+            .ok()
+            .unwrap()
+        );
+
+        assert!(login_password(
+            &server_setup,
+            &new_registration_record,
+            NEW_PASSWORD,
+            key_stretching()
+        )
+        .is_some());
+        assert!(login(&server_setup, &new_registration_record, key_stretching()).is_none());
+    }
+
+    #[test]
+    fn derive_key_from_export_key() {
+        let export_key = base64_encode([1; 64]);
+        let derive = |label: &str, length, context: Option<&str>| {
+            derive_key(DeriveKeyFromExportKeyParams {
+                export_key: export_key.clone(),
+                label: label.to_string(),
+                length,
+                context: context.map(str::to_string),
+            })
+        };
+
+        let key = derive("vault-encryption", 32, None).ok().unwrap();
+        assert_eq!(
+            base64_encode(&key),
+            "CmxndyHvfoxLDJMjonti_aHP7-7jAITwUnXDlKDcGng"
+        );
+        assert_eq!(derive("vault-encryption", 32, None).ok().unwrap(), key);
+        assert_ne!(derive("signing", 32, None).ok().unwrap(), key);
+        assert_ne!(
+            derive("vault-encryption", 32, Some("device")).ok().unwrap(),
+            key
+        );
+        assert_eq!(derive("vault-encryption", 64, None).ok().unwrap().len(), 64);
+
+        assert!(derive("", 32, None).is_err());
+        assert!(derive("vault-encryption", 0, None).is_err());
+        assert!(derive("vault-encryption", 255 * 64 + 1, None).is_err());
+    }
+
+    #[test]
+    fn encrypt_with_export_key() {
+        let export_key = base64_encode([1; 64]);
+        let plaintext = base64_encode(b"vault contents");
+        let associated_data = || Some(base64_encode(b"vault-id"));
+        let encrypted = || {
+            encrypt(EncryptWithExportKeyParams {
+                export_key: export_key.clone(),
+                plaintext: plaintext.clone(),
+                associated_data: associated_data(),
+            })
+            .ok()
+            .unwrap()
+        };
+        let decrypt_with = |export_key, ciphertext: &[u8], associated_data| {
+            decrypt(DecryptWithExportKeyParams {
+                export_key,
+                ciphertext: base64_encode(ciphertext),
+                associated_data,
+            })
+        };
+
+        let ciphertext = encrypted();
+        assert_eq!(ciphertext[0], 1);
+        assert_ne!(ciphertext, encrypted());
+        let decrypted = decrypt_with(export_key.clone(), &ciphertext, associated_data());
+        assert_eq!(base64_encode(decrypted.ok().unwrap()), plaintext);
+
+        let code = |result: JsResult<Vec<u8>>| result.err().unwrap().code();
+        assert_eq!(
+            code(decrypt_with(export_key.clone(), &ciphertext, None)),
+            ErrorCode::DecryptionFailed
+        );
+        assert_eq!(
+            code(decrypt_with(
+                base64_encode([2; 64]),
+                &ciphertext,
+                associated_data()
+            )),
+            ErrorCode::DecryptionFailed
+        );
+        let mut tampered = ciphertext.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            code(decrypt_with(
+                export_key.clone(),
+                &tampered,
+                associated_data()
+            )),
+            ErrorCode::DecryptionFailed
+        );
+        let mut unknown_version = ciphertext;
+        unknown_version[0] = 2;
+        assert_eq!(
+            code(decrypt_with(
+                export_key,
+                &unknown_version,
+                associated_data()
+            )),
+            ErrorCode::InvalidParameter
+        );
+    }
+
+    #[test]
+    fn secure_channel() {
+        let server_setup = create_server_setup(None);
+        let key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+        let registration_record = register(&server_setup, None, key_stretching());
+        let (client_session_key, server_session_key) =
+            login(&server_setup, &registration_record, key_stretching()).unwrap();
+        let channel = |session_key: &String, role, channel_id| {
+            let session_key = base64_decode("sessionKey", session_key).ok().unwrap();
+            SecureChannel::from_session_key(&session_key, role, [channel_id; 16])
+        };
+        let mut client = channel(&client_session_key, ChannelRole::Client, 1);
+        let mut server = channel(&server_session_key, ChannelRole::Server, 2);
+
+        let first = client.seal_bytes(b"first", b"rpc").ok().unwrap();
+        let second = client.seal_bytes(b"second", b"rpc").ok().unwrap();
+        let code = |result: JsResult<Vec<u8>>| result.err().unwrap().code();
+
+        // Reordered messages are rejected without advancing the counter
+        assert_eq!(
+            code(server.open_bytes(&second, b"rpc")),
+            ErrorCode::MessageOutOfOrder
+        );
+        assert_eq!(
+            code(server.open_bytes(&first, b"other")),
+            ErrorCode::DecryptionFailed
+        );
+        assert_eq!(server.open_bytes(&first, b"rpc").ok().unwrap(), b"first");
+        assert_eq!(
+            code(server.open_bytes(&first, b"rpc")),
+            ErrorCode::MessageOutOfOrder
+        );
+        assert_eq!(server.open_bytes(&second, b"rpc").ok().unwrap(), b"second");
+
+        // Each direction has its own key
+        let reply = server.seal_bytes(b"reply", b"").ok().unwrap();
+        let mut server_copy = channel(&server_session_key, ChannelRole::Server, 1);
+        assert_eq!(
+            code(server_copy.open_bytes(&reply, b"")),
+            ErrorCode::DecryptionFailed
+        );
+        assert_eq!(client.open_bytes(&reply, b"").ok().unwrap(), b"reply");
+
+        // A second channel of the same side sends with another key, which the
+        // other side only accepts before it received from the first channel
+        let mut other_server = channel(&server_session_key, ChannelRole::Server, 3);
+        let other_reply = other_server.seal_bytes(b"reply", b"").ok().unwrap();
+        assert_ne!(other_reply[17..], reply[17..]);
+        assert_eq!(
+            code(client.open_bytes(&other_reply, b"")),
+            ErrorCode::InvalidParameter
+        );
+        let mut other_client = channel(&client_session_key, ChannelRole::Client, 4);
+        assert_eq!(
+            other_client.open_bytes(&other_reply, b"").ok().unwrap(),
+            b"reply"
+        );
+    }
+
+    #[test]
+    fn client_sessions() {
+        let server_setup = create_server_setup(None);
+        let key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+
+        let registration = ClientRegistrationSession::start(StartClientRegistrationParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: None,
+        })
+        .ok()
+        .unwrap();
+        let server_reg_result =
+            create_server_registration_response(CreateServerRegistrationResponseParams {
+                server_setup: server_setup.clone(),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: registration.registration_request(),
+                cipher_suite: None,
+            })
+            .unwrap();
+        let registration_record = registration
+            .finish_registration(FinishClientRegistrationSessionParams {
+                key_stretching_function_config: key_stretching(),
+                shared: FinishClientRegistrationSharedParams {
+                    password: PASSWORD.to_string(),
+                    registration_response: server_reg_result.registration_response,
+                    ..Default::default()
+                },
+            })
+            .ok()
+            .unwrap()
+            .registration_record;
+
+        let login = ClientLoginSession::start(StartClientLoginParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: None,
+        })
+        .ok()
+        .unwrap();
+        let server_login_result = start_server_login(StartServerLoginParams {
+            server_setup,
+            registration_record: Some(registration_record),
+            start_login_request: login.start_login_request(),
+            user_identifier: USER_IDENTIFIER.to_string(),
+            identifiers: None,
+            cipher_suite: None,
+            credential_metadata: None,
+            context: None,
+        })
+        .unwrap();
+        let result = login
+            .finish_login(FinishClientLoginSessionParams {
+                key_stretching_function_config: key_stretching(),
+                shared: FinishClientLoginSharedParams {
+                    login_response: server_login_result.login_response,
+                    password: PASSWORD.to_string(),
+                    ..Default::default()
+                },
+            })
+            .ok()
+            .unwrap();
+        let client_finish_result = success(result).unwrap();
+
+        let server_finish_result = finish_server_login(FinishServerLoginParams {
+            server_login_state: server_login_result.server_login_state,
+            finish_login_request: client_finish_result.finish_login_request,
+            cipher_suite: None,
+        })
+        .unwrap();
+        assert_eq!(
+            client_finish_result.session_key,
+            server_finish_result.session_key
+        );
+    }
+
+    #[test]
+    fn server_public_key_pinning() {
+        let server_setup = create_server_setup(None);
+        let server_public_key = get_server_public_key(server_setup.clone(), None).unwrap();
+        let other_public_key = get_server_public_key(create_server_setup(None), None).unwrap();
+        let key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+        let pin = |pin| Options {
+            expected_server_public_key: Some(pin),
+            ..Default::default()
+        };
+
+        let registration_record = register_with(
+            &server_setup,
+            PASSWORD,
+            key_stretching(),
+            &pin(ServerPublicKeyPin::AnyOf(vec![
+                other_public_key.clone(),
+                server_public_key.clone(),
+            ])),
+        )
+        .ok()
+        .unwrap();
+        let error = register_with(
+            &server_setup,
+            PASSWORD,
+            key_stretching(),
+            &pin(ServerPublicKeyPin::Key(other_public_key.clone())),
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.code(), ErrorCode::ServerPublicKeyMismatch);
+
+        let login = |options: &Options| {
+            login_with(
+                &server_setup,
+                &registration_record,
+                PASSWORD,
+                key_stretching(),
+                options,
+            )
+        };
+        let result = login(&pin(ServerPublicKeyPin::Key(server_public_key)));
+        assert!(result.ok().unwrap().is_ok());
+        let error = login(&pin(ServerPublicKeyPin::Key(other_public_key)))
+            .err()
+            .unwrap();
+        assert_eq!(error.code(), ErrorCode::ServerPublicKeyMismatch);
+    }
+
+    #[test]
+    fn login_context() {
+        let key_stretching = || Some(KeyStretchingFunctionConfig::Identity);
+
+        for cipher_suite in [None, Some(CipherSuiteConfig::Ristretto255MlKem768)] {
+            let server_setup = create_server_setup(cipher_suite);
+            let registration_record = register(&server_setup, cipher_suite, key_stretching());
+
+            let login = |server_context: Option<&str>, client_context: Option<&str>| {
+                let options = Options {
+                    cipher_suite,
+                    client_context: client_context.map(str::to_string),
+                    server_context: server_context.map(str::to_string),
+                    ..Default::default()
+                };
+                login_with(
+                    &server_setup,
+                    &registration_record,
+                    PASSWORD,
+                    key_stretching(),
+                    &options,
+                )
+                .ok()
+                .unwrap()
+            };
+
+            assert!(login(Some("tenant-a"), Some("tenant-a")).is_ok());
+            assert_eq!(
+                login(Some("tenant-a"), Some("tenant-b")).err(),
+                Some(LoginFailureReason::InvalidCredentials)
+            );
+            assert_eq!(
+                login(Some("tenant-a"), None).err(),
+                Some(LoginFailureReason::InvalidCredentials)
+            );
+        }
+    }
+
+    #[test]
+    fn key_stretching_task() {
+        let server_setup = create_server_setup(None);
+        let key_stretching =
+            || Some(KeyStretchingFunctionConfig::Pbkdf2Sha512Custom { iterations: 5000 });
+
+        let client_reg_result = start_client_registration(StartClientRegistrationParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: None,
+        })
+        .unwrap();
+        let server_reg_result =
+            create_server_registration_response(CreateServerRegistrationResponseParams {
+                server_setup: server_setup.clone(),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: client_reg_result.registration_request,
+                cipher_suite: None,
+            })
+            .unwrap();
+        let mut task = KeyStretchingTask::for_client_registration(FinishClientRegistrationParams {
+            client_registration_state: client_reg_result.client_registration_state,
+            key_stretching_function_config: key_stretching(),
+            cipher_suite: None,
+            shared: FinishClientRegistrationSharedParams {
+                password: PASSWORD.to_string(),
+                registration_response: server_reg_result.registration_response,
+                ..Default::default()
+            },
+        })
+        .ok()
+        .unwrap();
+        while !task.step(10.0).ok().unwrap() {}
+        assert_eq!(task.progress(), 1.0);
+        let registration_record = task
+            .finish_client_registration()
+            .ok()
+            .unwrap()
+            .registration_record;
+
+        // The record is the same as one created by `finishClientRegistration`
+        assert!(login(&server_setup, &registration_record, key_stretching()).is_some());
+
+        let client_login_result = start_client_login(StartClientLoginParams {
+            password: PASSWORD.to_string(),
+            cipher_suite: None,
+        })
+        .unwrap();
+        let server_login_result = start_server_login(StartServerLoginParams {
+            server_setup: server_setup.clone(),
+            registration_record: Some(registration_record),
+            start_login_request: client_login_result.start_login_request,
+            user_identifier: USER_IDENTIFIER.to_string(),
+            identifiers: None,
+            cipher_suite: None,
+            credential_metadata: None,
+            context: None,
+        })
+        .unwrap();
+        let mut task = KeyStretchingTask::for_client_login(FinishClientLoginParams {
+            client_login_state: client_login_result.client_login_state,
+            key_stretching_function_config: key_stretching(),
+            cipher_suite: None,
+            shared: FinishClientLoginSharedParams {
+                login_response: server_login_result.login_response,
+                password: PASSWORD.to_string(),
+                ..Default::default()
+            },
+        })
+        .ok()
+        .unwrap();
+
+        // Without a budget every step only runs a single chunk of iterations
+        let mut progress = 0.0;
+        while !task.step(0.0).ok().unwrap() {
+            assert!(task.progress() > progress);
+            progress = task.progress();
+        }
+        assert!(progress > 0.0);
+        let client_finish_result = success(task.finish_client_login().ok().unwrap()).unwrap();
+
+        let server_finish_result = finish_server_login(FinishServerLoginParams {
+            server_login_state: server_login_result.server_login_state,
+            finish_login_request: client_finish_result.finish_login_request,
+            cipher_suite: None,
+        })
+        .unwrap();
+        assert_eq!(
+            client_finish_result.session_key,
+            server_finish_result.session_key
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "argon2")]
+    fn key_stretching_task_argon2() {
+        let server_setup = create_server_setup(None);
+        let argon2 = |algorithm, version, parallelism, output_length| {
+            Some(KeyStretchingFunctionConfig::Argon2Custom {
+                algorithm: Some(algorithm),
+                version: Some(version),
+                iterations: 2,
+                memory: 1024,
+                parallelism,
+                output_length,
+            })
+        };
+        let options = Options {
+            pepper: Some(base64_encode(b"pepper")),
+            ..Options::default()
+        };
+        for (key_stretching, options) in [
+            (
+                argon2(
+                    Argon2AlgorithmConfig::Argon2id,
+                    Argon2VersionConfig::V0x13,
+                    1,
+                    None,
+                ),
+                Options::default(),
+            ),
+            (
+                argon2(
+                    Argon2AlgorithmConfig::Argon2i,
+                    Argon2VersionConfig::V0x10,
+                    3,
+                    Some(32),
+                ),
+                options.clone(),
+            ),
+            (
+                argon2(
+                    Argon2AlgorithmConfig::Argon2d,
+                    Argon2VersionConfig::V0x13,
+                    2,
+                    Some(80),
+                ),
+                options,
+            ),
+        ] {
+            let client_reg_result = start_client_registration(StartClientRegistrationParams {
+                password: PASSWORD.to_string(),
+                cipher_suite: None,
+            })
+            .unwrap();
+            let server_reg_result = registration_response(CreateServerRegistrationResponseParams {
+                server_setup: server_setup.clone(),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: client_reg_result.registration_request.clone(),
+                cipher_suite: None,
+            })
+            .ok()
             .unwrap();
+            let params = || FinishClientRegistrationParams {
+                client_registration_state: client_reg_result.client_registration_state.clone(),
+                key_stretching_function_config: key_stretching.clone(),
+                cipher_suite: None,
+                shared: FinishClientRegistrationSharedParams {
+                    password: PASSWORD.to_string(),
+                    registration_response: server_reg_result.registration_response.clone(),
+                    pepper: options.pepper.clone(),
+                    ..Default::default()
+                },
+            };
+
+            // Aborting releases the job, later steps fail
+            let mut task = KeyStretchingTask::for_client_registration(params())
+                .ok()
+                .unwrap();
+            assert!(!task.run_step(0.0).ok().unwrap());
+            assert!(task.progress() > 0.0);
+            task.abort();
+            assert_eq!(task.progress(), 0.0);
+            assert_eq!(
+                task.run_step(0.0).err().unwrap().code(),
+                ErrorCode::InvalidState
+            );
+
+            // Without a budget every step only fills a single segment
+            let mut task = KeyStretchingTask::for_client_registration(params())
+                .ok()
+                .unwrap();
+            let (mut progress, mut steps) = (0.0, 1);
+            while !task.step(0.0).ok().unwrap() {
+                assert!(task.progress() > progress);
+                progress = task.progress();
+                steps += 1;
+            }
+            assert!(steps >= 2 * 4);
+            assert_eq!(task.progress(), 1.0);
+            let registration_record = task
+                .finish_client_registration()
+                .ok()
+                .unwrap()
+                .registration_record;
+
+            // The record is the same as one created with the argon2 crate
+            assert!(login_with(
+                &server_setup,
+                &registration_record,
+                PASSWORD,
+                key_stretching,
+                &options
+            )
+            .ok()
+            .unwrap()
+            .is_ok());
+        }
+    }
+
+    const USER_IDENTIFIER: &str = "john.doe@example.com";
+    const PASSWORD: &str = "_P4ssw0rd123!";
+
+    /// Options of a test registration or login beyond the password and the
+    /// key stretching function; both sides use the same cipher suite and
+    /// identifiers.
+    #[derive(Default, Clone)]
+    struct Options {
+        cipher_suite: Option<CipherSuiteConfig>,
+        identifiers: Option<CustomIdentifiers>,
+        ksf_salt: Option<KsfSaltConfig>,
+        pepper: Option<String>,
+        expected_server_public_key: Option<ServerPublicKeyPin>,
+        client_context: Option<String>,
+        server_context: Option<String>,
+    }
 
-            client_finish_result.registration_record
+    fn register(
+        server_setup: &str,
+        cipher_suite: Option<CipherSuiteConfig>,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) -> String {
+        register_password(server_setup, PASSWORD, cipher_suite, key_stretching)
+    }
+
+    fn register_password(
+        server_setup: &str,
+        password: &str,
+        cipher_suite: Option<CipherSuiteConfig>,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) -> String {
+        let options = Options {
+            cipher_suite,
+            ..Default::default()
         };
+        register_with(server_setup, password, key_stretching, &options)
+            .ok()
+            .unwrap()
+    }
+
+    /// Returns the registration record, or the error of the client finishing
+    /// the registration.
+    fn register_with(
+        server_setup: &str,
+        password: &str,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+        options: &Options,
+    ) -> JsResult<String> {
+        // Client starts registration
+        let client_reg_result = start_client_registration(StartClientRegistrationParams {
+            password: password.to_string(),
+            cipher_suite: options.cipher_suite,
+        })
+        .unwrap();
+
+        // Server handles registration request
+        let server_reg_result =
+            create_server_registration_response(CreateServerRegistrationResponseParams {
+                server_setup: server_setup.to_string(),
+                user_identifier: USER_IDENTIFIER.to_string(),
+                registration_request: client_reg_result.registration_request,
+                cipher_suite: options.cipher_suite,
+            })
+            .unwrap();
+
+        // Client finishes registration
+        let params = FinishClientRegistrationParams {
+            client_registration_state: client_reg_result.client_registration_state,
+            key_stretching_function_config: key_stretching,
+            cipher_suite: options.cipher_suite,
+            shared: FinishClientRegistrationSharedParams {
+                password: password.to_string(),
+                registration_response: server_reg_result.registration_response,
+                identifiers: options.identifiers.clone(),
+                ksf_salt: options.ksf_salt.clone(),
+                pepper: options.pepper.clone(),
+                expected_server_public_key: options.expected_server_public_key.clone(),
+                ..Default::default()
+            },
+        };
+        let custom_ksf = get_registration_ksf(
+            &params.shared,
+            params.key_stretching_function_config.clone(),
+        )?;
+        let client_finish_result = finish_registration(&params, custom_ksf.as_ref())?;
+
+        Ok(client_finish_result.registration_record)
+    }
+
+    fn success(result: FinishClientLoginResult) -> Option<FinishClientLoginSuccess> {
+        match result {
+            FinishClientLoginResult::Success(success) => Some(success),
+            FinishClientLoginResult::Failure(_) => None,
+        }
+    }
+
+    fn failure_reason(result: FinishClientLoginResult) -> Option<LoginFailureReason> {
+        match result {
+            FinishClientLoginResult::Success(_) => None,
+            FinishClientLoginResult::Failure(failure) => Some(failure.reason),
+        }
+    }
+
+    /// Returns the client and server session keys of a successful login.
+    fn login(
+        server_setup: &str,
+        registration_record: &str,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) -> Option<(String, String)> {
+        login_password(server_setup, registration_record, PASSWORD, key_stretching)
+    }
+
+    fn login_password(
+        server_setup: &str,
+        registration_record: &str,
+        password: &str,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) -> Option<(String, String)> {
+        login_with(
+            server_setup,
+            registration_record,
+            password,
+            key_stretching,
+            &Options::default(),
+        )
+        .ok()
+        .unwrap()
+        .ok()
+    }
+
+    /// Returns the client and server session keys of a successful login, the
+    /// reason of a failed login, or the error of the client finishing the login.
+    fn login_with(
+        server_setup: &str,
+        registration_record: &str,
+        password: &str,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+        options: &Options,
+    ) -> JsResult<Result<(String, String), LoginFailureReason>> {
+        let client_login_result = start_client_login(StartClientLoginParams {
+            password: password.to_string(),
+            cipher_suite: options.cipher_suite,
+        })
+        .unwrap();
+        let server_login_result = start_server_login(StartServerLoginParams {
+            server_setup: server_setup.to_string(),
+            registration_record: Some(registration_record.to_string()),
+            start_login_request: client_login_result.start_login_request,
+            user_identifier: USER_IDENTIFIER.to_string(),
+            identifiers: options.identifiers.clone(),
+            cipher_suite: options.cipher_suite,
+            credential_metadata: None,
+            context: options.server_context.clone(),
+        })
+        .unwrap();
+        let params = FinishClientLoginParams {
+            client_login_state: client_login_result.client_login_state,
+            key_stretching_function_config: key_stretching,
+            cipher_suite: options.cipher_suite,
+            shared: FinishClientLoginSharedParams {
+                login_response: server_login_result.login_response,
+                password: password.to_string(),
+                identifiers: options.identifiers.clone(),
+                ksf_salt: options.ksf_salt.clone(),
+                pepper: options.pepper.clone(),
+                expected_server_public_key: options.expected_server_public_key.clone(),
+                context: options.client_context.clone(),
+                ..Default::default()
+            },
+        };
+        let custom_ksf = get_login_ksf(
+            &params.shared,
+            params.key_stretching_function_config.clone(),
+            params.cipher_suite,
+        )?;
+        let client_finish_result = match finish_login(&params, custom_ksf.as_ref())? {
+            FinishClientLoginResult::Success(success) => success,
+            FinishClientLoginResult::Failure(failure) => return Ok(Err(failure.reason)),
+        };
+        let server_finish_result = finish_server_login(FinishServerLoginParams {
+            server_login_state: server_login_result.server_login_state,
+            finish_login_request: client_finish_result.finish_login_request,
+            cipher_suite: options.cipher_suite,
+        })
+        .ok()
+        .unwrap();
+        Ok(Ok((
+            client_finish_result.session_key,
+            server_finish_result.session_key,
+        )))
+    }
+
+    fn run_key_exchange(
+        cipher_suite: Option<CipherSuiteConfig>,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) {
+        // Server configuration
+        let server_setup = create_server_setup(cipher_suite);
+
+        // Client configuration
+        let user_identifier = USER_IDENTIFIER;
+        let password = PASSWORD;
+
+        // Registration
+        let registration_record = register(&server_setup, cipher_suite, key_stretching.clone());
 
         // Login
         {
             // Client starts login
             let client_login_result = start_client_login(StartClientLoginParams {
                 password: password.to_string(),
+                cipher_suite,
             })
             .unwrap();
 
@@ -69,25 +1569,33 @@ mod tests {
                 start_login_request: client_login_result.start_login_request,
                 user_identifier: user_identifier.to_string(),
                 identifiers: None,
+                cipher_suite,
+                credential_metadata: None,
+                context: None,
             })
             .unwrap();
 
             // Client finishes login
             let client_finish_result = finish_client_login(FinishClientLoginParams {
                 client_login_state: client_login_result.client_login_state,
-                login_response: server_login_result.login_response,
-                password: password.to_string(),
-                identifiers: None,
-                key_stretching_function_config: None,
+                key_stretching_function_config: key_stretching,
+                cipher_suite,
+                shared: FinishClientLoginSharedParams {
+                    login_response: server_login_result.login_response,
+                    password: password.to_string(),
+                    ..Default::default()
+                },
             })
             .unwrap();
 
-            let client_finish_result = client_finish_result.expect("Client login should succeed");
+            let client_finish_result =
+                success(client_finish_result).expect("Client login should succeed");
 
             // Server finishes login
             let server_finish_result = finish_server_login(FinishServerLoginParams {
                 server_login_state: server_login_result.server_login_state,
                 finish_login_request: client_finish_result.finish_login_request,
+                cipher_suite,
             })
             .unwrap();
 
@@ -0,0 +1,317 @@
+use std::convert::{TryFrom, TryInto};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use opaque_ke::errors::{InternalError, ProtocolError};
+use sha2::Sha512;
+
+use crate::{
+    base64::{base64_decode, base64_encode, JsResult},
+    cipher_suite::CipherSuiteConfig,
+    error::Error,
+    ksf::{Argon2AlgorithmConfig, Argon2VersionConfig, KeyStretchingFunctionConfig, KsfSaltConfig},
+};
+
+const VERSION: u8 = 1;
+const MAC_LEN: usize = 64;
+const STR_METADATA_KEY: &[u8] = b"OPAQUE-Credential-Metadata";
+
+/// Parameters the client used in `finishClientRegistration`, stored by the
+/// server next to the registration record so that `finishClientLogin` does
+/// not need them to be passed again.
+///
+/// The serialized form is `version || cipher suite || key stretching ||
+/// salt policy || HMAC-SHA512`, where the MAC is keyed with a key derived
+/// from the export key and can therefore only be checked after a login.
+pub(crate) struct CredentialMetadata {
+    pub(crate) cipher_suite: CipherSuiteConfig,
+    /// `None` for a callback, which has to be passed to `finishClientLogin` again
+    pub(crate) key_stretching: Option<KeyStretchingFunctionConfig>,
+    pub(crate) ksf_salt: KsfSaltConfig,
+}
+
+impl CredentialMetadata {
+    pub(crate) fn new(
+        cipher_suite: Option<CipherSuiteConfig>,
+        key_stretching: &Option<KeyStretchingFunctionConfig>,
+        ksf_salt: &Option<KsfSaltConfig>,
+    ) -> Self {
+        CredentialMetadata {
+            cipher_suite: cipher_suite.unwrap_or_default(),
+            key_stretching: match key_stretching.clone().unwrap_or_default() {
+                KeyStretchingFunctionConfig::Callback { .. } => None,
+                config => Some(config),
+            },
+            ksf_salt: ksf_salt.clone().unwrap_or_default(),
+        }
+    }
+
+    pub(crate) fn serialize(&self, export_key: &[u8]) -> JsResult<Vec<u8>> {
+        let mut bytes = vec![VERSION, cipher_suite_id(self.cipher_suite)];
+        write_key_stretching(&mut bytes, self.key_stretching.as_ref());
+        write_ksf_salt(&mut bytes, &self.ksf_salt)?;
+        let mac = mac(export_key).chain_update(&bytes).finalize().into_bytes();
+        bytes.extend(mac);
+        Ok(bytes)
+    }
+
+    /// Returns the key stretching and salt configs to use in `finishClientLogin`.
+    /// The function of a callback has to be passed as `key_stretching` again.
+    pub(crate) fn into_login_config(
+        self,
+        cipher_suite: Option<CipherSuiteConfig>,
+        key_stretching: Option<KeyStretchingFunctionConfig>,
+    ) -> JsResult<(KeyStretchingFunctionConfig, KsfSaltConfig)> {
+        if self.cipher_suite != cipher_suite.unwrap_or_default() {
+            return Err(Error::CipherSuiteMismatch {
+                context: "credentialMetadata",
+            });
+        }
+        let key_stretching = match (self.key_stretching, key_stretching) {
+            (Some(config), _) => config,
+            (None, Some(callback @ KeyStretchingFunctionConfig::Callback { .. })) => callback,
+            (None, _) => {
+                return Err(Error::Internal {
+                    context: "credentialMetadata requires the keyStretching callback",
+                    error: InternalError::KsfError,
+                })
+            }
+        };
+        Ok((key_stretching, self.ksf_salt))
+    }
+
+    /// Parses the metadata without checking the MAC, see [`verify`].
+    pub(crate) fn deserialize(bytes: &[u8]) -> JsResult<Self> {
+        let deserialize = || -> Option<Self> {
+            let mut reader = Reader(bytes.get(..bytes.len().checked_sub(MAC_LEN)?)?);
+            if reader.u8()? != VERSION {
+                return None;
+            }
+            let metadata = CredentialMetadata {
+                cipher_suite: cipher_suite_from_id(reader.u8()?)?,
+                key_stretching: read_key_stretching(&mut reader)?,
+                ksf_salt: read_ksf_salt(&mut reader)?,
+            };
+            reader.0.is_empty().then_some(metadata)
+        };
+        deserialize().ok_or(Error::Protocol {
+            context: "deserialize credentialMetadata",
+            error: ProtocolError::SerializationError,
+        })
+    }
+}
+
+/// Checks the MAC of serialized metadata with the export key of a login.
+pub(crate) fn verify(bytes: &[u8], export_key: &[u8]) -> bool {
+    let (body, tag) = bytes.split_at(bytes.len().saturating_sub(MAC_LEN));
+    mac(export_key).chain_update(body).verify_slice(tag).is_ok()
+}
+
+fn mac(export_key: &[u8]) -> Hmac<Sha512> {
+    let mut key = [0; MAC_LEN];
+    Hkdf::<Sha512>::new(None, export_key)
+        .expand(STR_METADATA_KEY, &mut key)
+        .expect("MAC key length is a valid HKDF-SHA512 output length");
+    Hmac::<Sha512>::new_from_slice(&key).expect("HMAC accepts keys of any length")
+}
+
+fn cipher_suite_id(cipher_suite: CipherSuiteConfig) -> u8 {
+    match cipher_suite {
+        CipherSuiteConfig::Ristretto255 => 0,
+        CipherSuiteConfig::P256 => 1,
+        CipherSuiteConfig::P384 => 2,
+        CipherSuiteConfig::P521 => 3,
+        CipherSuiteConfig::Ristretto255MlKem768 => 4,
+    }
+}
+
+fn cipher_suite_from_id(id: u8) -> Option<CipherSuiteConfig> {
+    Some(match id {
+        0 => CipherSuiteConfig::Ristretto255,
+        1 => CipherSuiteConfig::P256,
+        2 => CipherSuiteConfig::P384,
+        3 => CipherSuiteConfig::P521,
+        4 => CipherSuiteConfig::Ristretto255MlKem768,
+        _ => return None,
+    })
+}
+
+fn write_key_stretching(bytes: &mut Vec<u8>, config: Option<&KeyStretchingFunctionConfig>) {
+    let config = match config {
+        Some(config) => config,
+        None => {
+            bytes.push(13);
+            return;
+        }
+    };
+    match config {
+        KeyStretchingFunctionConfig::Rfc9106Recommended => bytes.push(0),
+        KeyStretchingFunctionConfig::LibsodiumModerate => bytes.push(1),
+        KeyStretchingFunctionConfig::MemoryConstrained => bytes.push(2),
+        KeyStretchingFunctionConfig::Custom {
+            iterations,
+            memory,
+            parallelism,
+        } => {
+            bytes.push(3);
+            for value in [iterations, memory, parallelism] {
+                bytes.extend(value.to_be_bytes());
+            }
+        }
+        KeyStretchingFunctionConfig::Argon2Custom {
+            algorithm,
+            version,
+            iterations,
+            memory,
+            parallelism,
+            output_length,
+        } => {
+            bytes.push(4);
+            bytes.push(match algorithm.unwrap_or_default() {
+                Argon2AlgorithmConfig::Argon2d => 0,
+                Argon2AlgorithmConfig::Argon2i => 1,
+                Argon2AlgorithmConfig::Argon2id => 2,
+            });
+            bytes.push(match version.unwrap_or_default() {
+                Argon2VersionConfig::V0x10 => 0x10,
+                Argon2VersionConfig::V0x13 => 0x13,
+            });
+            // An output length of 0 stands for the OPRF output length
+            for value in [
+                *iterations,
+                *memory,
+                *parallelism,
+                output_length.unwrap_or(0),
+            ] {
+                bytes.extend(value.to_be_bytes());
+            }
+        }
+        KeyStretchingFunctionConfig::ScryptInteractive => bytes.push(5),
+        KeyStretchingFunctionConfig::ScryptRecommended => bytes.push(6),
+        KeyStretchingFunctionConfig::ScryptCustom { n, r, p } => {
+            bytes.push(7);
+            bytes.extend(n.to_be_bytes());
+            bytes.extend(r.to_be_bytes());
+            bytes.extend(p.to_be_bytes());
+        }
+        KeyStretchingFunctionConfig::Pbkdf2Sha256Recommended => bytes.push(8),
+        KeyStretchingFunctionConfig::Pbkdf2Sha512Recommended => bytes.push(9),
+        KeyStretchingFunctionConfig::Pbkdf2Sha256Custom { iterations } => {
+            bytes.push(10);
+            bytes.extend(iterations.to_be_bytes());
+        }
+        KeyStretchingFunctionConfig::Pbkdf2Sha512Custom { iterations } => {
+            bytes.push(11);
+            bytes.extend(iterations.to_be_bytes());
+        }
+        KeyStretchingFunctionConfig::Identity => bytes.push(12),
+        KeyStretchingFunctionConfig::Callback { .. } => bytes.push(13),
+    }
+}
+
+fn read_key_stretching(reader: &mut Reader) -> Option<Option<KeyStretchingFunctionConfig>> {
+    Some(Some(match reader.u8()? {
+        0 => KeyStretchingFunctionConfig::Rfc9106Recommended,
+        1 => KeyStretchingFunctionConfig::LibsodiumModerate,
+        2 => KeyStretchingFunctionConfig::MemoryConstrained,
+        3 => KeyStretchingFunctionConfig::Custom {
+            iterations: reader.u32()?,
+            memory: reader.u32()?,
+            parallelism: reader.u32()?,
+        },
+        4 => KeyStretchingFunctionConfig::Argon2Custom {
+            algorithm: Some(match reader.u8()? {
+                0 => Argon2AlgorithmConfig::Argon2d,
+                1 => Argon2AlgorithmConfig::Argon2i,
+                2 => Argon2AlgorithmConfig::Argon2id,
+                _ => return None,
+            }),
+            version: Some(match reader.u8()? {
+                0x10 => Argon2VersionConfig::V0x10,
+                0x13 => Argon2VersionConfig::V0x13,
+                _ => return None,
+            }),
+            iterations: reader.u32()?,
+            memory: reader.u32()?,
+            parallelism: reader.u32()?,
+            output_length: Some(reader.u32()?).filter(|len| *len != 0),
+        },
+        5 => KeyStretchingFunctionConfig::ScryptInteractive,
+        6 => KeyStretchingFunctionConfig::ScryptRecommended,
+        7 => KeyStretchingFunctionConfig::ScryptCustom {
+            n: reader.u64()?,
+            r: reader.u32()?,
+            p: reader.u32()?,
+        },
+        8 => KeyStretchingFunctionConfig::Pbkdf2Sha256Recommended,
+        9 => KeyStretchingFunctionConfig::Pbkdf2Sha512Recommended,
+        10 => KeyStretchingFunctionConfig::Pbkdf2Sha256Custom {
+            iterations: reader.u32()?,
+        },
+        11 => KeyStretchingFunctionConfig::Pbkdf2Sha512Custom {
+            iterations: reader.u32()?,
+        },
+        12 => KeyStretchingFunctionConfig::Identity,
+        13 => return Some(None),
+        _ => return None,
+    }))
+}
+
+fn write_ksf_salt(bytes: &mut Vec<u8>, config: &KsfSaltConfig) -> JsResult<()> {
+    match config {
+        KsfSaltConfig::Zero => bytes.push(0),
+        KsfSaltConfig::Identifiers => bytes.push(1),
+        KsfSaltConfig::Custom(salt) => {
+            let salt = base64_decode("ksfSalt", salt)?;
+            let len = u16::try_from(salt.len()).map_err(|_| Error::Internal {
+                context: "Invalid ksfSalt (at most 65535 bytes are supported)",
+                error: InternalError::KsfError,
+            })?;
+            bytes.push(2);
+            bytes.extend(len.to_be_bytes());
+            bytes.extend(salt);
+        }
+    }
+    Ok(())
+}
+
+fn read_ksf_salt(reader: &mut Reader) -> Option<KsfSaltConfig> {
+    Some(match reader.u8()? {
+        0 => KsfSaltConfig::Zero,
+        1 => KsfSaltConfig::Identifiers,
+        2 => {
+            let len = reader.u16()?;
+            KsfSaltConfig::Custom(base64_encode(reader.bytes(len as usize)?))
+        }
+        _ => return None,
+    })
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_arch = "wasm32")]
+use js_sys::Reflect;
+#[cfg(target_arch = "wasm32")]
+use wasm_bindgen::prelude::*;
+
+#[cfg(target_arch = "wasm32")]
+use crate::error::Error;
+
+/// Set once the worker threads of the rayon thread pool are running. Native
+/// builds use OS threads and need no initialization.
+static THREAD_POOL_READY: AtomicBool = AtomicBool::new(cfg!(not(target_arch = "wasm32")));
+
+/// Set once Argon2 ran before the thread pool was ready. rayon then falls back
+/// to a global pool of just the calling thread, which cannot be replaced.
+static SINGLE_THREADED: AtomicBool = AtomicBool::new(false);
+
+/// Starts `num_threads` web workers for computing Argon2 lanes in
+/// `finishClientLogin` and `finishClientRegistration`; `KeyStretchingTask`
+/// computes them one after the other on the calling thread. Has to be awaited
+/// before the first key stretching, which otherwise computes the lanes on the
+/// calling thread from then on.
+///
+/// Throws unless the page is cross-origin isolated, as the workers share the
+/// memory through a `SharedArrayBuffer`, and on the main browser thread, which
+/// cannot block while the workers compute. `loadOpaque` of the package's
+/// `loader` entry only loads this build where it can run.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen(js_name = initKeyStretchingThreads)]
+pub fn init_key_stretching_threads(num_threads: usize) -> Result<js_sys::Promise, JsError> {
+    let global = js_sys::global();
+    let property = |name: &str| Reflect::get(&global, &JsValue::from_str(name)).unwrap_or_default();
+    if !property("crossOriginIsolated").is_truthy() || property("SharedArrayBuffer").is_undefined()
+    {
+        return Err(Error::InvalidState {
+            context: "initKeyStretchingThreads requires a cross-origin isolated page",
+        }
+        .into());
+    }
+    if !property("document").is_undefined() {
+        return Err(Error::InvalidState {
+            context: "initKeyStretchingThreads cannot run on the main browser thread",
+        }
+        .into());
+    }
+    if SINGLE_THREADED.load(Ordering::Acquire) {
+        return Err(Error::InvalidState {
+            context: "initKeyStretchingThreads has to be awaited before the first keyStretching",
+        }
+        .into());
+    }
+
+    let ready = Closure::new(|_: JsValue| THREAD_POOL_READY.store(true, Ordering::Release));
+    let promise = wasm_bindgen_rayon::init_thread_pool(num_threads).then(&ready);
+    ready.forget();
+    Ok(promise)
+}
+
+/// Called before Argon2 uses the rayon thread pool.
+pub(crate) fn start_argon2() {
+    if !THREAD_POOL_READY.load(Ordering::Acquire) {
+        SINGLE_THREADED.store(true, Ordering::Release);
+    }
+}
@@ -1,39 +1,43 @@
+use generic_array::typenum::Unsigned;
 use opaque_ke::rand::rngs::OsRng;
 use opaque_ke::{
-    CredentialFinalization, CredentialRequest, RegistrationRequest, ServerLogin,
-    ServerLoginStartParameters, ServerRegistration, ServerSetup,
+    CredentialFinalization, CredentialFinalizationLen, CredentialRequest, CredentialRequestLen,
+    RegistrationRequest, RegistrationRequestLen, ServerLogin, ServerLoginStartParameters,
+    ServerRegistration, ServerRegistrationLen, ServerSetup,
 };
 use serde::{Deserialize, Serialize};
 use tsify::Tsify;
 use wasm_bindgen::prelude::*;
 
-use crate::base64::JsResult;
 use crate::{
-    base64::{base64_decode, base64_encode},
-    cipher_suite::DefaultCipherSuite,
+    base64::{base64_decode, base64_encode, JsResult},
+    cipher_suite::{check_message_len, message_lens, with_cipher_suite, CipherSuiteConfig},
     error::from_protocol_error,
     identifiers::{get_identifiers, CustomIdentifiers},
+    kem, upgrade,
 };
 
 #[wasm_bindgen(js_name = createServerSetup)]
-pub fn create_server_setup() -> String {
+pub fn create_server_setup(cipher_suite: Option<CipherSuiteConfig>) -> String {
     let mut rng: OsRng = OsRng;
-    let setup = ServerSetup::<DefaultCipherSuite>::new(&mut rng);
-    base64_encode(setup.serialize())
-}
-
-fn decode_server_setup(data: String) -> JsResult<ServerSetup<DefaultCipherSuite>> {
-    base64_decode("serverSetup", data).and_then(|bytes| {
-        ServerSetup::<DefaultCipherSuite>::deserialize(&bytes)
-            .map_err(from_protocol_error("deserialize serverSetup"))
+    with_cipher_suite!(cipher_suite, CS => {
+        let setup = ServerSetup::<CS>::new(&mut rng);
+        base64_encode(setup.serialize())
     })
 }
 
 #[wasm_bindgen(js_name = getServerPublicKey)]
-pub fn get_server_public_key(data: String) -> Result<String, JsError> {
-    let server_setup = decode_server_setup(data)?;
-    let pub_key = server_setup.keypair().public().serialize();
-    Ok(base64_encode(pub_key))
+pub fn get_server_public_key(
+    data: String,
+    cipher_suite: Option<CipherSuiteConfig>,
+) -> Result<String, JsError> {
+    let server_setup_bytes = base64_decode("serverSetup", data)?;
+    with_cipher_suite!(cipher_suite, CS => {
+        let server_setup = ServerSetup::<CS>::deserialize(&server_setup_bytes)
+            .map_err(from_protocol_error("deserialize serverSetup"))?;
+        let pub_key = server_setup.keypair().public().serialize();
+        Ok(base64_encode(pub_key))
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -45,6 +49,9 @@ pub struct CreateServerRegistrationResponseParams {
     pub(crate) user_identifier: String,
     #[serde(rename = "registrationRequest")]
     pub(crate) registration_request: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -58,20 +65,37 @@ pub struct CreateServerRegistrationResponseResult {
 pub fn create_server_registration_response(
     params: CreateServerRegistrationResponseParams,
 ) -> Result<CreateServerRegistrationResponseResult, JsError> {
-    let server_setup = decode_server_setup(params.server_setup)?;
+    Ok(registration_response(params)?)
+}
+
+pub(crate) fn registration_response(
+    params: CreateServerRegistrationResponseParams,
+) -> JsResult<CreateServerRegistrationResponseResult> {
+    let server_setup_bytes = base64_decode("serverSetup", params.server_setup)?;
     let registration_request_bytes =
         base64_decode("registrationRequest", params.registration_request)?;
-    let server_registration_start_result = ServerRegistration::<DefaultCipherSuite>::start(
-        &server_setup,
-        RegistrationRequest::deserialize(&registration_request_bytes)
-            .map_err(from_protocol_error("deserialize registrationRequest"))?,
-        params.user_identifier.as_bytes(),
-    )
-    .map_err(from_protocol_error("start server registration"))?;
-    let registration_response_bytes = server_registration_start_result.message.serialize();
-
-    Ok(CreateServerRegistrationResponseResult {
-        registration_response: base64_encode(registration_response_bytes),
+
+    with_cipher_suite!(params.cipher_suite, CS => {
+        check_message_len(
+            "registrationRequest",
+            &registration_request_bytes,
+            RegistrationRequestLen::<CS>::USIZE,
+            message_lens!(RegistrationRequestLen),
+        )?;
+        let server_setup = ServerSetup::<CS>::deserialize(&server_setup_bytes)
+            .map_err(from_protocol_error("deserialize serverSetup"))?;
+        let server_registration_start_result = ServerRegistration::<CS>::start(
+            &server_setup,
+            RegistrationRequest::deserialize(&registration_request_bytes)
+                .map_err(from_protocol_error("deserialize registrationRequest"))?,
+            params.user_identifier.as_bytes(),
+        )
+        .map_err(from_protocol_error("start server registration"))?;
+        let registration_response_bytes = server_registration_start_result.message.serialize();
+
+        Ok(CreateServerRegistrationResponseResult {
+            registration_response: base64_encode(registration_response_bytes),
+        })
     })
 }
 
@@ -89,6 +113,17 @@ pub struct StartServerLoginParams {
     pub(crate) user_identifier: String,
     #[tsify(optional)]
     pub(crate) identifiers: Option<CustomIdentifiers>,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
+    /// Metadata stored next to the registration record, returned unchanged
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+    /// Application or channel context the session is bound to, the client has
+    /// to pass the same `context` to `finishClientLogin`
+    #[tsify(optional)]
+    pub(crate) context: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -98,51 +133,98 @@ pub struct StartServerLoginResult {
     pub(crate) server_login_state: String,
     #[serde(rename = "loginResponse")]
     pub(crate) login_response: String,
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
 }
 
 #[wasm_bindgen(js_name = startServerLogin)]
 pub fn start_server_login(
     params: StartServerLoginParams,
 ) -> Result<StartServerLoginResult, JsError> {
-    let server_setup = decode_server_setup(params.server_setup)?;
+    let server_setup_bytes = base64_decode("serverSetup", params.server_setup)?;
     let registration_record_bytes = match params.registration_record {
         Some(pw) => base64_decode("registrationRecord", pw).map(Some),
         None => Ok(None),
     }?;
-    let credential_request_bytes = base64_decode("startLoginRequest", params.start_login_request)?;
+    let mut credential_request_bytes =
+        base64_decode("startLoginRequest", params.start_login_request)?;
 
     let mut rng: OsRng = OsRng;
+    let context = params.context.as_ref().map(String::as_bytes);
 
-    let registration_record = match registration_record_bytes.as_ref() {
-        Some(bytes) => Some(
-            ServerRegistration::<DefaultCipherSuite>::deserialize(bytes)
-                .map_err(from_protocol_error("deserialize registrationRecord"))?,
-        ),
-        None => None,
+    // (binding context, ciphertext, shared secret) of the ML-KEM part of a hybrid login
+    let ml_kem = if params.cipher_suite.unwrap_or_default().uses_ml_kem() {
+        let encapsulation_key = kem::split_off(
+            "startLoginRequest",
+            &mut credential_request_bytes,
+            kem::ENCAPSULATION_KEY_LEN,
+        )?;
+        let (ciphertext, shared_secret) = kem::encapsulate(&mut rng, &encapsulation_key)?;
+        let context = kem::binding_context(&encapsulation_key, &ciphertext, context);
+        Some((context, ciphertext, shared_secret))
+    } else {
+        None
     };
 
-    let start_params = ServerLoginStartParameters {
-        identifiers: get_identifiers(&params.identifiers),
-        context: None,
-    };
+    let (mut message, mut state) = with_cipher_suite!(params.cipher_suite, CS => {
+        check_message_len(
+            "startLoginRequest",
+            &credential_request_bytes,
+            CredentialRequestLen::<CS>::USIZE,
+            message_lens!(CredentialRequestLen),
+        )?;
+        let server_setup = ServerSetup::<CS>::deserialize(&server_setup_bytes)
+            .map_err(from_protocol_error("deserialize serverSetup"))?;
+
+        let registration_record = match registration_record_bytes.as_ref() {
+            Some(bytes) => Some({
+                check_message_len(
+                    "registrationRecord",
+                    bytes,
+                    ServerRegistrationLen::<CS>::USIZE,
+                    message_lens!(ServerRegistrationLen),
+                )?;
+                ServerRegistration::<CS>::deserialize(bytes)
+                    .map_err(from_protocol_error("deserialize registrationRecord"))?
+            }),
+            None => None,
+        };
 
-    let server_login_start_result = ServerLogin::start(
-        &mut rng,
-        &server_setup,
-        registration_record,
-        CredentialRequest::deserialize(&credential_request_bytes)
-            .map_err(from_protocol_error("deserialize startLoginRequest"))?,
-        params.user_identifier.as_bytes(),
-        start_params,
-    )
-    .map_err(from_protocol_error("start server login"))?;
+        let start_params = ServerLoginStartParameters {
+            identifiers: get_identifiers(&params.identifiers),
+            context: match &ml_kem {
+                Some((context, _, _)) => Some(context.as_slice()),
+                None => context,
+            },
+        };
 
-    let login_response = base64_encode(server_login_start_result.message.serialize());
-    let server_login_state = base64_encode(server_login_start_result.state.serialize());
+        let server_login_start_result = ServerLogin::start(
+            &mut rng,
+            &server_setup,
+            registration_record,
+            CredentialRequest::deserialize(&credential_request_bytes)
+                .map_err(from_protocol_error("deserialize startLoginRequest"))?,
+            params.user_identifier.as_bytes(),
+            start_params,
+        )
+        .map_err(from_protocol_error("start server login"))?;
+
+        (
+            server_login_start_result.message.serialize().to_vec(),
+            server_login_start_result.state.serialize().to_vec(),
+        )
+    });
+
+    if let Some((_, ciphertext, shared_secret)) = ml_kem {
+        message.extend(ciphertext);
+        state.extend(shared_secret);
+    }
 
     let result = StartServerLoginResult {
-        server_login_state,
-        login_response,
+        server_login_state: base64_encode(state),
+        login_response: base64_encode(message),
+        credential_metadata: params.credential_metadata,
     };
     Ok(result)
 }
@@ -154,6 +236,9 @@ pub struct FinishServerLoginParams {
     pub(crate) server_login_state: String,
     #[serde(rename = "finishLoginRequest")]
     pub(crate) finish_login_request: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Tsify)]
@@ -169,16 +254,147 @@ pub fn finish_server_login(
 ) -> Result<FinishServerLoginResult, JsError> {
     let credential_finalization_bytes =
         base64_decode("finishLoginRequest", params.finish_login_request)?;
-    let state_bytes = base64_decode("serverLoginState", params.server_login_state)?;
-    let state = ServerLogin::<DefaultCipherSuite>::deserialize(&state_bytes)
-        .map_err(from_protocol_error("deserialize serverLoginState"))?;
-    let server_login_finish_result = state
-        .finish(
-            CredentialFinalization::deserialize(&credential_finalization_bytes)
-                .map_err(from_protocol_error("deserialize finishLoginRequest"))?,
-        )
-        .map_err(from_protocol_error("finish server login"))?;
-    Ok(FinishServerLoginResult {
-        session_key: base64_encode(server_login_finish_result.session_key),
+    let mut state_bytes = base64_decode("serverLoginState", params.server_login_state)?;
+
+    let ml_kem_shared_secret = if params.cipher_suite.unwrap_or_default().uses_ml_kem() {
+        Some(kem::split_off(
+            "serverLoginState",
+            &mut state_bytes,
+            kem::SHARED_SECRET_LEN,
+        )?)
+    } else {
+        None
+    };
+
+    with_cipher_suite!(params.cipher_suite, CS => {
+        check_message_len(
+            "finishLoginRequest",
+            &credential_finalization_bytes,
+            CredentialFinalizationLen::<CS>::USIZE,
+            message_lens!(CredentialFinalizationLen),
+        )?;
+        let state = ServerLogin::<CS>::deserialize(&state_bytes)
+            .map_err(from_protocol_error("deserialize serverLoginState"))?;
+        let server_login_finish_result = state
+            .finish(
+                CredentialFinalization::deserialize(&credential_finalization_bytes)
+                    .map_err(from_protocol_error("deserialize finishLoginRequest"))?,
+            )
+            .map_err(from_protocol_error("finish server login"))?;
+
+        let session_key = match &ml_kem_shared_secret {
+            Some(shared_secret) => {
+                kem::combine_session_key(&server_login_finish_result.session_key, shared_secret)
+            }
+            None => server_login_finish_result.session_key.to_vec(),
+        };
+
+        Ok(FinishServerLoginResult {
+            session_key: base64_encode(session_key),
+        })
     })
 }
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct VerifyRegistrationUpgradeParams {
+    /// Session key of the login the upgrade was authenticated with
+    #[serde(rename = "sessionKey")]
+    pub(crate) session_key: String,
+    #[serde(rename = "registrationRecord")]
+    pub(crate) registration_record: String,
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+    #[serde(rename = "upgradeTag")]
+    pub(crate) upgrade_tag: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
+}
+
+/// Returns whether the new registration record was sent by the client of the
+/// given login session and may replace the stored record.
+#[wasm_bindgen(js_name = verifyRegistrationUpgrade)]
+pub fn verify_registration_upgrade(
+    params: VerifyRegistrationUpgradeParams,
+) -> Result<bool, JsError> {
+    let upgrade_tag = base64_decode("upgradeTag", params.upgrade_tag)?;
+    Ok(verify_record_tag(
+        upgrade::STR_UPGRADE_KEY,
+        params.session_key,
+        params.registration_record,
+        params.credential_metadata,
+        &upgrade_tag,
+        params.cipher_suite,
+    )?)
+}
+
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct VerifyPasswordChangeParams {
+    /// Session key returned by `finishServerLogin` for the login with the old
+    /// password
+    #[serde(rename = "sessionKey")]
+    pub(crate) session_key: String,
+    #[serde(rename = "registrationRecord")]
+    pub(crate) registration_record: String,
+    #[tsify(optional)]
+    #[serde(rename = "credentialMetadata")]
+    pub(crate) credential_metadata: Option<String>,
+    #[serde(rename = "passwordChangeTag")]
+    pub(crate) password_change_tag: String,
+    #[tsify(optional)]
+    #[serde(rename = "cipherSuite")]
+    pub(crate) cipher_suite: Option<CipherSuiteConfig>,
+}
+
+/// Returns whether the registration record of the new password was sent by
+/// the client of the given login session and may replace the stored record.
+#[wasm_bindgen(js_name = verifyPasswordChange)]
+pub fn verify_password_change(params: VerifyPasswordChangeParams) -> Result<bool, JsError> {
+    let password_change_tag = base64_decode("passwordChangeTag", params.password_change_tag)?;
+    Ok(verify_record_tag(
+        upgrade::STR_PASSWORD_CHANGE_KEY,
+        params.session_key,
+        params.registration_record,
+        params.credential_metadata,
+        &password_change_tag,
+        params.cipher_suite,
+    )?)
+}
+
+fn verify_record_tag(
+    label: &[u8],
+    session_key: String,
+    registration_record: String,
+    credential_metadata: Option<String>,
+    tag: &[u8],
+    cipher_suite: Option<CipherSuiteConfig>,
+) -> JsResult<bool> {
+    let session_key = base64_decode("sessionKey", session_key)?;
+    let registration_record_bytes = base64_decode("registrationRecord", registration_record)?;
+    let credential_metadata = match credential_metadata {
+        Some(metadata) => base64_decode("credentialMetadata", metadata)?,
+        None => Vec::new(),
+    };
+
+    with_cipher_suite!(cipher_suite, CS => {
+        check_message_len(
+            "registrationRecord",
+            &registration_record_bytes,
+            ServerRegistrationLen::<CS>::USIZE,
+            message_lens!(ServerRegistrationLen),
+        )?;
+        ServerRegistration::<CS>::deserialize(&registration_record_bytes)
+            .map_err(from_protocol_error("deserialize registrationRecord"))?;
+    });
+
+    Ok(upgrade::verify(
+        label,
+        &session_key,
+        &registration_record_bytes,
+        &credential_metadata,
+        tag,
+    ))
+}
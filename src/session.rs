@@ -0,0 +1,172 @@
+use serde::{Deserialize, Serialize};
+use tsify::Tsify;
+use wasm_bindgen::prelude::*;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{
+    base64::{base64_encode, JsResult},
+    cipher_suite::CipherSuiteConfig,
+    client::{
+        finish_login_with_state, finish_registration_with_state, get_login_ksf,
+        get_registration_ksf, start_login, start_registration, FinishClientLoginResult,
+        FinishClientLoginSharedParams, FinishClientRegistrationResult,
+        FinishClientRegistrationSharedParams, StartClientLoginParams,
+        StartClientRegistrationParams,
+    },
+    ksf::KeyStretchingFunctionConfig,
+};
+
+/// `FinishClientLoginParams` without the state and cipher suite kept by the
+/// session.
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct FinishClientLoginSessionParams {
+    #[tsify(optional)]
+    #[serde(rename = "keyStretching")]
+    pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[serde(flatten)]
+    pub(crate) shared: FinishClientLoginSharedParams,
+}
+
+/// Client side of a login that keeps the login state in wasm memory instead
+/// of handing it to JavaScript as `clientLoginState`. The state is zeroized
+/// when the session is finished or freed.
+#[wasm_bindgen]
+pub struct ClientLoginSession {
+    state: Zeroizing<Vec<u8>>,
+    start_login_request: String,
+    cipher_suite: Option<CipherSuiteConfig>,
+}
+
+impl ClientLoginSession {
+    pub(crate) fn start(mut params: StartClientLoginParams) -> JsResult<Self> {
+        let result = start_login(&params);
+        params.password.zeroize();
+        let (state, message) = result?;
+        Ok(ClientLoginSession {
+            state,
+            start_login_request: base64_encode(message),
+            cipher_suite: params.cipher_suite,
+        })
+    }
+
+    pub(crate) fn finish_login(
+        self,
+        params: FinishClientLoginSessionParams,
+    ) -> JsResult<FinishClientLoginResult> {
+        let FinishClientLoginSessionParams {
+            key_stretching_function_config,
+            mut shared,
+        } = params;
+        let result = get_login_ksf(&shared, key_stretching_function_config, self.cipher_suite)
+            .and_then(|custom_ksf| {
+                finish_login_with_state(&shared, self.cipher_suite, self.state, custom_ksf.as_ref())
+            });
+        shared.password.zeroize();
+        result
+    }
+}
+
+#[wasm_bindgen]
+impl ClientLoginSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(params: StartClientLoginParams) -> Result<ClientLoginSession, JsError> {
+        Ok(ClientLoginSession::start(params)?)
+    }
+
+    /// Has to be sent to the server for `startServerLogin`.
+    #[wasm_bindgen(getter, js_name = startLoginRequest)]
+    pub fn start_login_request(&self) -> String {
+        self.start_login_request.clone()
+    }
+
+    /// Finishes the login like `finishClientLogin` and consumes the session.
+    pub fn finish(
+        self,
+        params: FinishClientLoginSessionParams,
+    ) -> Result<FinishClientLoginResult, JsError> {
+        Ok(self.finish_login(params)?)
+    }
+}
+
+/// `FinishClientRegistrationParams` without the state and cipher suite kept by
+/// the session.
+#[derive(Debug, Serialize, Deserialize, Tsify)]
+#[tsify(into_wasm_abi, from_wasm_abi)]
+pub struct FinishClientRegistrationSessionParams {
+    #[tsify(optional)]
+    #[serde(rename = "keyStretching")]
+    pub(crate) key_stretching_function_config: Option<KeyStretchingFunctionConfig>,
+    #[serde(flatten)]
+    pub(crate) shared: FinishClientRegistrationSharedParams,
+}
+
+/// Client side of a registration that keeps the registration state in wasm
+/// memory instead of handing it to JavaScript as `clientRegistrationState`.
+/// The state is zeroized when the session is finished or freed.
+#[wasm_bindgen]
+pub struct ClientRegistrationSession {
+    state: Zeroizing<Vec<u8>>,
+    registration_request: String,
+    cipher_suite: Option<CipherSuiteConfig>,
+}
+
+impl ClientRegistrationSession {
+    pub(crate) fn start(mut params: StartClientRegistrationParams) -> JsResult<Self> {
+        let result = start_registration(&params);
+        params.password.zeroize();
+        let (state, message) = result?;
+        Ok(ClientRegistrationSession {
+            state: Zeroizing::new(state),
+            registration_request: base64_encode(message),
+            cipher_suite: params.cipher_suite,
+        })
+    }
+
+    pub(crate) fn finish_registration(
+        self,
+        params: FinishClientRegistrationSessionParams,
+    ) -> JsResult<FinishClientRegistrationResult> {
+        let FinishClientRegistrationSessionParams {
+            key_stretching_function_config,
+            mut shared,
+        } = params;
+        let result = get_registration_ksf(&shared, key_stretching_function_config.clone())
+            .and_then(|custom_ksf| {
+                finish_registration_with_state(
+                    &shared,
+                    &key_stretching_function_config,
+                    self.cipher_suite,
+                    self.state,
+                    custom_ksf.as_ref(),
+                )
+            });
+        shared.password.zeroize();
+        result
+    }
+}
+
+#[wasm_bindgen]
+impl ClientRegistrationSession {
+    #[wasm_bindgen(constructor)]
+    pub fn new(
+        params: StartClientRegistrationParams,
+    ) -> Result<ClientRegistrationSession, JsError> {
+        Ok(ClientRegistrationSession::start(params)?)
+    }
+
+    /// Has to be sent to the server for `createServerRegistrationResponse`.
+    #[wasm_bindgen(getter, js_name = registrationRequest)]
+    pub fn registration_request(&self) -> String {
+        self.registration_request.clone()
+    }
+
+    /// Finishes the registration like `finishClientRegistration` and consumes
+    /// the session.
+    pub fn finish(
+        self,
+        params: FinishClientRegistrationSessionParams,
+    ) -> Result<FinishClientRegistrationResult, JsError> {
+        Ok(self.finish_registration(params)?)
+    }
+}
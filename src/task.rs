@@ -0,0 +1,149 @@
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    base64::JsResult,
+    client::{
+        finish_login, finish_registration, get_login_ksf, get_registration_ksf,
+        FinishClientLoginParams, FinishClientLoginResult, FinishClientRegistrationParams,
+        FinishClientRegistrationResult,
+    },
+    error::Error,
+    ksf::{CustomKsf, KeyStretchingJob},
+};
+
+enum Flow {
+    Login(FinishClientLoginParams),
+    Registration(FinishClientRegistrationParams),
+}
+
+impl Flow {
+    fn finish(&self, custom_ksf: &CustomKsf) -> JsResult<()> {
+        match self {
+            Flow::Login(params) => finish_login(params, Some(custom_ksf)).map(|_| ()),
+            Flow::Registration(params) => finish_registration(params, Some(custom_ksf)).map(|_| ()),
+        }
+    }
+}
+
+/// Runs the key stretching of `finishClientLogin` or `finishClientRegistration`
+/// in steps, so that the UI can show its progress and cancel it. Argon2 and
+/// PBKDF2 are split into steps of the given budget, the other functions finish
+/// in the first step. The task is single-threaded: Argon2 lanes are computed
+/// one after the other, also once `initKeyStretchingThreads` has been called.
+#[wasm_bindgen]
+pub struct KeyStretchingTask {
+    flow: Flow,
+    job: Option<KeyStretchingJob>,
+}
+
+impl KeyStretchingTask {
+    fn new(flow: Flow, custom_ksf: Option<CustomKsf>) -> JsResult<Self> {
+        // Running the flow with a capturing function yields the input of the
+        // key stretching function, the flow itself fails afterwards
+        let capture = CustomKsf::capture();
+        let result = flow.finish(&capture);
+        let input = match (capture.captured_input(), result) {
+            (Some(input), _) => input,
+            (None, Err(error)) => return Err(error),
+            (None, Ok(())) => {
+                return Err(Error::InvalidState {
+                    context: "keyStretchingTask did not reach the key stretching function",
+                })
+            }
+        };
+
+        Ok(KeyStretchingTask {
+            flow,
+            job: Some(custom_ksf.unwrap_or_default().into_job(input)),
+        })
+    }
+
+    fn into_precomputed(self) -> JsResult<(Flow, CustomKsf)> {
+        let job = self.job.ok_or(Error::InvalidState {
+            context: "keyStretchingTask was aborted",
+        })?;
+        let custom_ksf = job.into_precomputed().ok_or(Error::InvalidState {
+            context: "keyStretchingTask is not finished",
+        })?;
+        Ok((self.flow, custom_ksf))
+    }
+
+    pub(crate) fn run_step(&mut self, budget_ms: f64) -> JsResult<bool> {
+        let job = self.job.as_mut().ok_or(Error::InvalidState {
+            context: "keyStretchingTask was aborted",
+        })?;
+        job.step(budget_ms).map_err(|_| {
+            job.memory_error().unwrap_or(Error::KsfFailed {
+                context: "keyStretchingTask step",
+            })
+        })
+    }
+}
+
+#[wasm_bindgen]
+impl KeyStretchingTask {
+    #[wasm_bindgen(js_name = forClientLogin)]
+    pub fn for_client_login(params: FinishClientLoginParams) -> Result<KeyStretchingTask, JsError> {
+        let custom_ksf = get_login_ksf(
+            &params.shared,
+            params.key_stretching_function_config.clone(),
+            params.cipher_suite,
+        )?;
+        Ok(KeyStretchingTask::new(Flow::Login(params), custom_ksf)?)
+    }
+
+    #[wasm_bindgen(js_name = forClientRegistration)]
+    pub fn for_client_registration(
+        params: FinishClientRegistrationParams,
+    ) -> Result<KeyStretchingTask, JsError> {
+        let custom_ksf = get_registration_ksf(
+            &params.shared,
+            params.key_stretching_function_config.clone(),
+        )?;
+        Ok(KeyStretchingTask::new(
+            Flow::Registration(params),
+            custom_ksf,
+        )?)
+    }
+
+    /// Computes for about `budget_ms` milliseconds and returns whether the
+    /// key stretching is finished.
+    pub fn step(&mut self, budget_ms: f64) -> Result<bool, JsError> {
+        Ok(self.run_step(budget_ms)?)
+    }
+
+    /// Fraction of the key stretching done, between 0 and 1.
+    #[wasm_bindgen(getter)]
+    pub fn progress(&self) -> f64 {
+        self.job.as_ref().map_or(0.0, KeyStretchingJob::progress)
+    }
+
+    /// Stops the key stretching and releases its memory.
+    pub fn abort(&mut self) {
+        self.job = None;
+    }
+
+    #[wasm_bindgen(js_name = finishClientLogin)]
+    pub fn finish_client_login(self) -> Result<FinishClientLoginResult, JsError> {
+        match self.into_precomputed()? {
+            (Flow::Login(params), custom_ksf) => Ok(finish_login(&params, Some(&custom_ksf))?),
+            (Flow::Registration(_), _) => Err(Error::InvalidState {
+                context: "keyStretchingTask was created for a registration",
+            }
+            .into()),
+        }
+    }
+
+    #[wasm_bindgen(js_name = finishClientRegistration)]
+    pub fn finish_client_registration(self) -> Result<FinishClientRegistrationResult, JsError> {
+        match self.into_precomputed()? {
+            (Flow::Registration(params), custom_ksf) => {
+                Ok(finish_registration(&params, Some(&custom_ksf))?)
+            }
+            (Flow::Login(_), _) => Err(Error::InvalidState {
+                context: "keyStretchingTask was created for a login",
+            }
+            .into()),
+        }
+    }
+}
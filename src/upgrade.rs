@@ -0,0 +1,53 @@
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+
+const MAC_LEN: usize = 64;
+pub(crate) const STR_UPGRADE_KEY: &[u8] = b"OPAQUE-Registration-Upgrade";
+pub(crate) const STR_PASSWORD_CHANGE_KEY: &[u8] = b"OPAQUE-Password-Change";
+
+/// MAC over a registration record (and its credential metadata) created
+/// after a successful login, keyed with a key derived from the session key so
+/// that the server only replaces a record for the client it just authenticated.
+/// The `label` separates registration upgrades from password changes.
+fn record_mac(
+    label: &[u8],
+    session_key: &[u8],
+    registration_record: &[u8],
+    metadata: &[u8],
+) -> Hmac<Sha512> {
+    let mut key = [0; MAC_LEN];
+    Hkdf::<Sha512>::new(None, session_key)
+        .expand(label, &mut key)
+        .expect("MAC key length is a valid HKDF-SHA512 output length");
+    let mut mac = Hmac::<Sha512>::new_from_slice(&key).expect("HMAC accepts keys of any length");
+    for bytes in [registration_record, metadata] {
+        mac.update(&(bytes.len() as u64).to_be_bytes());
+        mac.update(bytes);
+    }
+    mac
+}
+
+pub(crate) fn tag(
+    label: &[u8],
+    session_key: &[u8],
+    registration_record: &[u8],
+    metadata: &[u8],
+) -> Vec<u8> {
+    record_mac(label, session_key, registration_record, metadata)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+pub(crate) fn verify(
+    label: &[u8],
+    session_key: &[u8],
+    registration_record: &[u8],
+    metadata: &[u8],
+    tag: &[u8],
+) -> bool {
+    record_mac(label, session_key, registration_record, metadata)
+        .verify_slice(tag)
+        .is_ok()
+}
@@ -1,3 +1,6 @@
+#[cfg(feature = "argon2")]
+use std::collections::TryReserveError;
+
 pub fn set_panic_hook() {
     // When the `console_error_panic_hook` feature is enabled, we can call the
     // `set_panic_hook` function at least once during initialization, and then
@@ -8,3 +11,66 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// Milliseconds from an arbitrary origin, usable for measuring durations.
+/// `std::time::Instant` is not available on `wasm32-unknown-unknown`.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now_millis() -> f64 {
+    js_sys::Date::now()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now_millis() -> f64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs_f64() * 1000.0)
+        .unwrap_or_default()
+}
+
+#[cfg(target_arch = "wasm32")]
+const PAGE_KIB: u64 = 64;
+
+/// wasm32 memories are limited to 4 GiB; rustc declares a maximum of 1 GiB
+/// for the shared memory of builds with atomics, i.e. the `parallel` build.
+#[cfg(target_arch = "wasm32")]
+const MAX_PAGES: u64 = if cfg!(target_feature = "atomics") {
+    16384
+} else {
+    65536
+};
+
+/// Memory in KiB the wasm instance can address in total. Wasm memory never
+/// shrinks, but the allocator reuses what earlier key stretching released,
+/// so this and not the memory left to grow bounds a single computation.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn addressable_memory_kib() -> u64 {
+    MAX_PAGES * PAGE_KIB
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn addressable_memory_kib() -> u64 {
+    u64::MAX
+}
+
+/// Memory in KiB the wasm instance can still grow by.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn growable_memory_kib() -> u64 {
+    (MAX_PAGES - core::arch::wasm32::memory_size(0) as u64) * PAGE_KIB
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn growable_memory_kib() -> u64 {
+    u64::MAX
+}
+
+/// `vec![value; len]` that fails instead of aborting when the allocation
+/// cannot be satisfied, e.g. when the wasm instance cannot grow any further.
+#[cfg(feature = "argon2")]
+pub(crate) fn try_vec<T: Clone>(len: usize, value: T) -> Result<Vec<T>, TryReserveError> {
+    let mut vec = Vec::new();
+    vec.try_reserve_exact(len)?;
+    vec.resize(len, value);
+    Ok(vec)
+}
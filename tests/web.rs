@@ -3,11 +3,314 @@
 #![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
+use std::{cell::RefCell, rc::Rc};
+
+use js_sys::{Function, Object, Reflect, Uint8Array};
+use opaque_wasm::{
+    client::*,
+    server::*,
+    session::{ClientLoginSession, ClientRegistrationSession},
+    task::KeyStretchingTask,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
 use wasm_bindgen_test::*;
 
 wasm_bindgen_test_configure!(run_in_browser);
 
+const USER_IDENTIFIER: &str = "user@example.com";
+const PASSWORD: &str = "_P4ssw0rd123!";
+/// base64 of "callback-salt"
+const KSF_SALT: &str = "Y2FsbGJhY2stc2FsdA";
+
 #[wasm_bindgen_test]
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+fn object(entries: &[(&str, JsValue)]) -> JsValue {
+    let object = Object::new();
+    for (key, value) in entries {
+        Reflect::set(&object, &JsValue::from_str(key), value).unwrap();
+    }
+    object.into()
+}
+
+fn get(value: &JsValue, key: &str) -> JsValue {
+    Reflect::get(value, &JsValue::from_str(key)).unwrap()
+}
+
+fn from_js<T: DeserializeOwned>(value: JsValue) -> T {
+    serde_wasm_bindgen::from_value(value).unwrap()
+}
+
+fn to_js<T: Serialize>(value: &T) -> JsValue {
+    value
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .unwrap()
+}
+
+fn callback_key_stretching(function: &Function) -> JsValue {
+    object(&[("callback", object(&[("fn", function.clone().into())]))])
+}
+
+/// Starts a registration of `PASSWORD` and returns the server setup and the
+/// parameters of `finishClientRegistration` with the given key stretching
+/// config.
+fn registration_params(key_stretching: &JsValue) -> (String, JsValue) {
+    let server_setup = create_server_setup(None);
+    let client_reg_result = to_js(
+        &start_client_registration(from_js(object(&[("password", PASSWORD.into())]))).unwrap(),
+    );
+    let server_reg_result = to_js(
+        &create_server_registration_response(from_js(object(&[
+            ("serverSetup", server_setup.clone().into()),
+            ("userIdentifier", USER_IDENTIFIER.into()),
+            (
+                "registrationRequest",
+                get(&client_reg_result, "registrationRequest"),
+            ),
+        ])))
+        .unwrap(),
+    );
+    let params = object(&[
+        ("password", PASSWORD.into()),
+        (
+            "registrationResponse",
+            get(&server_reg_result, "registrationResponse"),
+        ),
+        (
+            "clientRegistrationState",
+            get(&client_reg_result, "clientRegistrationState"),
+        ),
+        ("keyStretching", key_stretching.clone()),
+        ("ksfSalt", object(&[("custom", KSF_SALT.into())])),
+    ]);
+    (server_setup, params)
+}
+
+/// Registers `PASSWORD` with the given key stretching config and returns the
+/// server setup and the registration result, or the thrown error.
+fn register(key_stretching: &JsValue) -> Result<(String, JsValue), JsValue> {
+    let (server_setup, params) = registration_params(key_stretching);
+    let result = finish_client_registration(from_js(params)).map_err(JsValue::from)?;
+    Ok((server_setup, to_js(&result)))
+}
+
+fn login(server_setup: String, registration_record: JsValue, key_stretching: &JsValue) -> JsValue {
+    let client_login_result =
+        to_js(&start_client_login(from_js(object(&[("password", PASSWORD.into())]))).unwrap());
+    let server_login_result = to_js(
+        &start_server_login(from_js(object(&[
+            ("serverSetup", server_setup.into()),
+            ("registrationRecord", registration_record),
+            (
+                "startLoginRequest",
+                get(&client_login_result, "startLoginRequest"),
+            ),
+            ("userIdentifier", USER_IDENTIFIER.into()),
+        ])))
+        .unwrap(),
+    );
+    let params = object(&[
+        (
+            "clientLoginState",
+            get(&client_login_result, "clientLoginState"),
+        ),
+        ("loginResponse", get(&server_login_result, "loginResponse")),
+        ("password", PASSWORD.into()),
+        ("keyStretching", key_stretching.clone()),
+        ("ksfSalt", object(&[("custom", KSF_SALT.into())])),
+    ]);
+    to_js(&finish_client_login(from_js(params)).unwrap())
+}
+
+fn error_code(error: &JsValue) -> Option<String> {
+    get(error, "code").as_string()
+}
+
+#[wasm_bindgen_test]
+fn callback_key_stretching_receives_salt() {
+    let salts = Rc::new(RefCell::new(Vec::new()));
+    let callback = {
+        let salts = salts.clone();
+        Closure::<dyn Fn(Uint8Array, Uint8Array) -> Uint8Array>::new(
+            move |input: Uint8Array, salt: Uint8Array| {
+                salts.borrow_mut().push(salt.to_vec());
+                let stretched = input
+                    .to_vec()
+                    .iter()
+                    .map(|byte| byte ^ 0x5c)
+                    .collect::<Vec<_>>();
+                Uint8Array::from(&stretched[..])
+            },
+        )
+    };
+    let key_stretching = callback_key_stretching(callback.as_ref().unchecked_ref());
+
+    let (server_setup, registration) = register(&key_stretching).unwrap();
+    let result = login(
+        server_setup,
+        get(&registration, "registrationRecord"),
+        &key_stretching,
+    );
+    assert_eq!(get(&result, "ok"), JsValue::TRUE);
+    assert_eq!(get(&result, "exportKey"), get(&registration, "exportKey"));
+    assert_eq!(
+        *salts.borrow(),
+        vec![b"callback-salt".to_vec(), b"callback-salt".to_vec()]
+    );
+}
+
+#[wasm_bindgen_test]
+fn callback_key_stretching_checks_output_length() {
+    let truncating = Function::new_with_args("input, salt", "return input.slice(1)");
+    let error = register(&callback_key_stretching(&truncating))
+        .err()
+        .unwrap();
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+
+    let not_bytes = Function::new_with_args("input, salt", "return Array.from(input)");
+    let error = register(&callback_key_stretching(&not_bytes))
+        .err()
+        .unwrap();
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+}
+
+#[wasm_bindgen_test]
+fn callback_key_stretching_rejects_async_and_throwing_functions() {
+    let throwing = Function::new_with_args("input, salt", "throw new Error('stretch failed')");
+    let error = register(&callback_key_stretching(&throwing)).err().unwrap();
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+
+    let asynchronous = Function::new_with_args("input, salt", "return Promise.resolve(input)");
+    let error = register(&callback_key_stretching(&asynchronous))
+        .err()
+        .unwrap();
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+}
+
+#[wasm_bindgen_test]
+fn key_stretching_failures_throw_ksf_failed() {
+    let throwing = Function::new_with_args("input, salt", "throw new Error('stretch failed')");
+    let key_stretching = callback_key_stretching(&throwing);
+
+    let error = register(&key_stretching).err().unwrap();
+    assert!(error.is_instance_of::<js_sys::Error>());
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+    assert_eq!(
+        get(&error, "context").as_string().as_deref(),
+        Some("finish client registration")
+    );
+
+    let (_, params) = registration_params(&key_stretching);
+    let mut task = KeyStretchingTask::for_client_registration(from_js(params)).unwrap();
+    let error = JsValue::from(task.step(10.0).err().unwrap());
+    assert!(error.is_instance_of::<js_sys::Error>());
+    assert_eq!(error_code(&error).as_deref(), Some("KSF_FAILED"));
+    assert_eq!(
+        get(&error, "context").as_string().as_deref(),
+        Some("keyStretchingTask step")
+    );
+}
+
+#[wasm_bindgen_test]
+fn sessions_accept_callback_key_stretching() {
+    let calls = Rc::new(RefCell::new(0));
+    let callback = {
+        let calls = calls.clone();
+        Closure::<dyn Fn(Uint8Array, Uint8Array) -> Uint8Array>::new(
+            move |input: Uint8Array, _salt: Uint8Array| {
+                *calls.borrow_mut() += 1;
+                input
+            },
+        )
+    };
+    let key_stretching = callback_key_stretching(callback.as_ref().unchecked_ref());
+    let server_setup = create_server_setup(None);
+
+    let registration =
+        ClientRegistrationSession::new(from_js(object(&[("password", PASSWORD.into())]))).unwrap();
+    let server_reg_result = to_js(
+        &create_server_registration_response(from_js(object(&[
+            ("serverSetup", server_setup.clone().into()),
+            ("userIdentifier", USER_IDENTIFIER.into()),
+            (
+                "registrationRequest",
+                registration.registration_request().into(),
+            ),
+        ])))
+        .unwrap(),
+    );
+    let registration = to_js(
+        &registration
+            .finish(from_js(object(&[
+                ("password", PASSWORD.into()),
+                (
+                    "registrationResponse",
+                    get(&server_reg_result, "registrationResponse"),
+                ),
+                ("keyStretching", key_stretching.clone()),
+            ])))
+            .unwrap(),
+    );
+
+    let login = ClientLoginSession::new(from_js(object(&[("password", PASSWORD.into())]))).unwrap();
+    let server_login_result = to_js(
+        &start_server_login(from_js(object(&[
+            ("serverSetup", server_setup.into()),
+            (
+                "registrationRecord",
+                get(&registration, "registrationRecord"),
+            ),
+            ("startLoginRequest", login.start_login_request().into()),
+            ("userIdentifier", USER_IDENTIFIER.into()),
+        ])))
+        .unwrap(),
+    );
+    let result = to_js(
+        &login
+            .finish(from_js(object(&[
+                ("loginResponse", get(&server_login_result, "loginResponse")),
+                ("password", PASSWORD.into()),
+                ("keyStretching", key_stretching),
+            ])))
+            .unwrap(),
+    );
+    assert_eq!(get(&result, "ok"), JsValue::TRUE);
+    assert_eq!(get(&result, "exportKey"), get(&registration, "exportKey"));
+    assert_eq!(*calls.borrow(), 2);
+}
+
+/// Grows the wasm memory until less than `free_kib` are left to grow and
+/// releases it again, like an earlier key stretching with a lot of memory.
+fn grow_memory_to_limit(free_kib: usize) {
+    const CHUNK: usize = 16 << 20;
+    let growable_kib = || (65536 - core::arch::wasm32::memory_size(0)) * 64;
+    let mut chunks = Vec::new();
+    while growable_kib() >= free_kib {
+        let mut chunk = Vec::<u8>::new();
+        chunk.try_reserve_exact(CHUNK).unwrap();
+        chunks.push(chunk);
+    }
+    assert!(growable_kib() < free_kib);
+}
+
+#[wasm_bindgen_test]
+fn key_stretching_reuses_released_memory() {
+    const MEMORY_KIB: u32 = 64 * 1024;
+    grow_memory_to_limit(MEMORY_KIB as usize);
+
+    let key_stretching = object(&[(
+        "argon2id-custom",
+        object(&[
+            ("iterations", 1.into()),
+            ("memory", MEMORY_KIB.into()),
+            ("parallelism", 1.into()),
+        ]),
+    )]);
+    for _ in 0..2 {
+        let (_, registration) = register(&key_stretching).unwrap();
+        assert!(get(&registration, "registrationRecord").is_string());
+    }
+}